@@ -31,6 +31,20 @@ pub fn nostrstore_derive(input: TokenStream) -> TokenStream {
                 value.push(self.clone());
                 value
             }
+
+            fn encode(
+                &self,
+                codec: nostrstore::CodecKind,
+            ) -> Result<String, nostrstore::codec::CodecError> {
+                codec.encode(self)
+            }
+
+            fn decode(
+                codec: nostrstore::CodecKind,
+                payload: String,
+            ) -> Result<#name, nostrstore::codec::CodecError> {
+                codec.decode(&payload)
+            }
         }
     };
 
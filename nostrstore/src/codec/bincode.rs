@@ -0,0 +1,20 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{Codec, CodecError};
+
+/// A codec that encodes values with bincode.
+/// It is the most compact codec, but the encoded values are not self-describing:
+/// the type used to read a value must match the one it was stored with.
+pub struct BincodeCodec;
+
+impl Codec for BincodeCodec {
+    const ID: &'static str = "bincode";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(::bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(::bincode::deserialize(bytes)?)
+    }
+}
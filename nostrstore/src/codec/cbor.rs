@@ -0,0 +1,20 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{Codec, CodecError};
+
+/// A codec that encodes values as CBOR.
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    const ID: &'static str = "cbor";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(value, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
@@ -0,0 +1,19 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{Codec, CodecError};
+
+/// A codec that encodes values as JSON.
+/// This is the default codec, and the format used by the `Operation` implementations.
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    const ID: &'static str = "json";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
@@ -0,0 +1,126 @@
+#[cfg(feature = "bincode")]
+pub mod bincode;
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub mod json;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::{Serialize, de::DeserializeOwned};
+
+#[cfg(feature = "bincode")]
+pub use self::bincode::BincodeCodec;
+#[cfg(feature = "cbor")]
+pub use cbor::CborCodec;
+pub use json::JsonCodec;
+#[cfg(feature = "msgpack")]
+pub use msgpack::MessagePackCodec;
+
+/// The error of a codec failing to encode or decode a value.
+pub type CodecError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A trait representing a format used to turn typed values into stored payloads and back.
+/// Implementations are used by the typed `put`/`get` methods of the `Database`, by event-operations
+/// storing serializable values and by aggregate events.
+pub trait Codec {
+    /// Identifier of the codec, stored in the tags of the events it encoded.
+    const ID: &'static str;
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError>;
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// The codecs available to a `Database`.
+/// It selects at runtime which `Codec` implementation values are encoded with.
+/// The binary codecs are enabled by the `cbor`, `msgpack` and `bincode` features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodecKind {
+    #[default]
+    Json,
+    #[cfg(feature = "cbor")]
+    Cbor,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+    #[cfg(feature = "bincode")]
+    Bincode,
+}
+
+impl CodecKind {
+    /// Returns the identifier of the codec.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Self::Json => JsonCodec::ID,
+            #[cfg(feature = "cbor")]
+            Self::Cbor => CborCodec::ID,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePackCodec::ID,
+            #[cfg(feature = "bincode")]
+            Self::Bincode => BincodeCodec::ID,
+        }
+    }
+
+    /// Returns the codec matching the given identifier, if it is enabled.
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            JsonCodec::ID => Some(Self::Json),
+            #[cfg(feature = "cbor")]
+            CborCodec::ID => Some(Self::Cbor),
+            #[cfg(feature = "msgpack")]
+            MessagePackCodec::ID => Some(Self::MessagePack),
+            #[cfg(feature = "bincode")]
+            BincodeCodec::ID => Some(Self::Bincode),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the encoded values carry their structure, such as field names.
+    /// Only these codecs can decode records whose optional fields are skipped when empty.
+    pub fn is_self_describing(&self) -> bool {
+        match self {
+            #[cfg(feature = "bincode")]
+            Self::Bincode => false,
+            _ => true,
+        }
+    }
+
+    /// Encodes the value into a payload that can be encrypted and stored.
+    /// Binary codecs are base64 encoded, since event contents must be valid strings.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<String, CodecError> {
+        let bytes = match self {
+            Self::Json => JsonCodec::encode(value)?,
+            #[cfg(feature = "cbor")]
+            Self::Cbor => CborCodec::encode(value)?,
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePackCodec::encode(value)?,
+            #[cfg(feature = "bincode")]
+            Self::Bincode => BincodeCodec::encode(value)?,
+        };
+
+        if *self == Self::Json {
+            Ok(String::from_utf8(bytes)?)
+        } else {
+            Ok(BASE64.encode(bytes))
+        }
+    }
+
+    /// Decodes a stored payload back into a value.
+    pub fn decode<T: DeserializeOwned>(&self, payload: &str) -> Result<T, CodecError> {
+        let bytes = if *self == Self::Json {
+            payload.as_bytes().to_vec()
+        } else {
+            BASE64.decode(payload)?
+        };
+
+        match self {
+            Self::Json => JsonCodec::decode(&bytes),
+            #[cfg(feature = "cbor")]
+            Self::Cbor => CborCodec::decode(&bytes),
+            #[cfg(feature = "msgpack")]
+            Self::MessagePack => MessagePackCodec::decode(&bytes),
+            #[cfg(feature = "bincode")]
+            Self::Bincode => BincodeCodec::decode(&bytes),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use super::{Codec, CodecError};
+
+/// A codec that encodes values as MessagePack.
+/// Structs are encoded as maps so that fields can be added without breaking older records.
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    const ID: &'static str = "msgpack";
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, CodecError> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
@@ -0,0 +1,70 @@
+/// The compression algorithms that can be applied to values before they are encrypted.
+/// Each algorithm is enabled by the feature of the same name (`zstd`, `deflate`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionKind {
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "deflate")]
+    Deflate,
+}
+
+// Without any compression feature the enum has no variants, and the arguments go unused.
+#[cfg_attr(
+    not(any(feature = "zstd", feature = "deflate")),
+    allow(unused_variables)
+)]
+impl CompressionKind {
+    /// Returns the identifier of the algorithm, stored in the tags of the compressed events.
+    pub fn id(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => "zstd",
+            #[cfg(feature = "deflate")]
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// Returns the algorithm matching the given identifier, if it is enabled.
+    pub fn from_id(id: &str) -> Option<Self> {
+        match id {
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Self::Zstd),
+            #[cfg(feature = "deflate")]
+            "deflate" => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    /// Compresses the given bytes.
+    pub fn compress(&self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::encode_all(bytes, zstd::DEFAULT_COMPRESSION_LEVEL),
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompresses bytes compressed with this algorithm.
+    pub fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        match *self {
+            #[cfg(feature = "zstd")]
+            Self::Zstd => zstd::decode_all(bytes),
+            #[cfg(feature = "deflate")]
+            Self::Deflate => {
+                use std::io::Read;
+
+                let mut decoded = Vec::new();
+                flate2::read::DeflateDecoder::new(bytes).read_to_end(&mut decoded)?;
+                Ok(decoded)
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Key of the record holding the access control list of a shared store.
+pub const ACL_KEY: &str = "nostrstore/acl";
+
+/// The key prefixes each writer of a shared store may write.
+/// It's published by the owner of the store with `SharedDatabase::set_acl`, as a record of the
+/// key `ACL_KEY`, so that its history can be audited with `read_history`. Once it's published,
+/// reads drop the records of the writers that may not write their key, and writes of those
+/// keys are refused. The owner may write any key.
+/// The list is stored like any other value of the store, so every member can read it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    writers: BTreeMap<PublicKey, BTreeSet<String>>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the given writer to write the keys starting with the given prefix.
+    pub fn allow<T: Into<String>>(mut self, writer: PublicKey, prefix: T) -> Self {
+        self.writers
+            .entry(writer)
+            .or_default()
+            .insert(prefix.into());
+        self
+    }
+
+    /// Returns the key prefixes the given writer may write.
+    pub fn prefixes(&self, writer: &PublicKey) -> impl Iterator<Item = &str> {
+        self.writers
+            .get(writer)
+            .into_iter()
+            .flat_map(|prefixes| prefixes.iter().map(String::as_str))
+    }
+
+    /// Returns whether the given writer may write the given key.
+    pub fn may_write(&self, writer: &PublicKey, key: &str) -> bool {
+        self.prefixes(writer).any(|prefix| key.starts_with(prefix))
+    }
+}
+
+/// The access control enforced on the reads and writes of a shared store.
+#[derive(Debug, Clone)]
+pub(crate) struct AccessControl {
+    /// The owner of the store, who publishes the list and may write any key.
+    pub owner: PublicKey,
+    /// The list published by the owner, if any. Without one, every writer may write any key.
+    pub acl: Option<Acl>,
+}
+
+impl AccessControl {
+    /// Returns whether the records of the given key written by the given author are read,
+    /// and whether the author may write the key. Only the owner writes the list itself.
+    pub fn allows(&self, author: &PublicKey, key: &str) -> bool {
+        if *author == self.owner {
+            return true;
+        }
+        key != ACL_KEY
+            && self
+                .acl
+                .as_ref()
+                .is_none_or(|acl| acl.may_write(author, key))
+    }
+}
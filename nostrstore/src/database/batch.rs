@@ -0,0 +1,248 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use nostr_sdk::prelude::*;
+use serde::Serialize;
+
+use super::core::{Database, NOSTR_STORE_BATCH_KIND, NOSTR_STORE_KIND};
+use super::record::CODEC_TAG;
+use super::{NostrRecord, QueryOptions};
+use crate::codec::{CodecError, CodecKind};
+use crate::{NostrDBError, Operation};
+
+/// Name of the tag holding the batch id and the position of a record within its batch.
+pub(crate) const BATCH_TAG: &str = "batch";
+
+/// Name of the tag flagging a record removing all the previous values of its key.
+pub(crate) const TOMBSTONE_TAG: &str = "tombstone";
+
+type Encoder = Box<dyn FnOnce(CodecKind) -> Result<String, CodecError> + Send>;
+
+enum BatchOp {
+    Store { key: String, content: String },
+    Put { key: String, encode: Encoder },
+    Event { key: String, encode: Encoder },
+    Remove { key: String },
+}
+
+impl BatchOp {
+    fn key(&self) -> &str {
+        match self {
+            Self::Store { key, .. }
+            | Self::Put { key, .. }
+            | Self::Event { key, .. }
+            | Self::Remove { key } => key,
+        }
+    }
+}
+
+/// A set of writes to several keys, committed atomically with `Database::commit`.
+/// Readers either see all the writes of a batch or none of them: the records of a batch
+/// are only visible once the commit marker of the batch has been published.
+#[derive(Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores a new key-value pair when the batch is committed.
+    pub fn store<T: Into<String>>(mut self, key: T, content: &str) -> Self {
+        self.ops.push(BatchOp::Store {
+            key: key.into(),
+            content: content.to_string(),
+        });
+        self
+    }
+
+    /// Stores a serializable value when the batch is committed.
+    /// The value is encoded with the codec configured for the key on the committing database.
+    pub fn put<K, T>(mut self, key: K, value: T) -> Self
+    where
+        K: Into<String>,
+        T: Serialize + Send + 'static,
+    {
+        self.ops.push(BatchOp::Put {
+            key: key.into(),
+            encode: Box::new(move |codec| codec.encode(&value)),
+        });
+        self
+    }
+
+    /// Stores an event-operation when the batch is committed.
+    /// The operation is encoded with the codec configured for the key on the committing database.
+    pub fn store_event<K, O>(mut self, key: K, operation: O) -> Self
+    where
+        K: Into<String>,
+        O: Operation + Send + 'static,
+    {
+        self.ops.push(BatchOp::Event {
+            key: key.into(),
+            encode: Box::new(move |codec| operation.encode(codec)),
+        });
+        self
+    }
+
+    /// Removes all values associated with the key when the batch is committed.
+    pub fn remove<T: Into<String>>(mut self, key: T) -> Self {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns whether the batch has no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Drops the records preceding the last tombstone of the set.
+/// The tombstone itself is kept, so that aggregating preserves the removal.
+pub(crate) fn apply_tombstones(records: BTreeSet<NostrRecord>) -> BTreeSet<NostrRecord> {
+    match records
+        .iter()
+        .rev()
+        .find(|record| record.tombstone)
+        .cloned()
+    {
+        Some(tombstone) => records
+            .into_iter()
+            .filter(|record| *record >= tombstone)
+            .collect(),
+        None => records,
+    }
+}
+
+/// Returns a new random write batch id.
+pub(crate) fn new_batch_id() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Returns the tag placing a record at the given position of a write batch.
+pub(crate) fn batch_tag(batch_id: &str, index: usize) -> Tag {
+    Tag::custom(
+        TagKind::custom(BATCH_TAG),
+        vec![batch_id.to_string(), index.to_string()],
+    )
+}
+
+/// Returns the commit marker making the records of a write batch visible.
+pub(crate) fn batch_marker(batch_id: &str) -> EventBuilder {
+    EventBuilder::new(Kind::Custom(NOSTR_STORE_BATCH_KIND), "").tag(Tag::custom(
+        TagKind::SingleLetter(SingleLetterTag::lowercase(Alphabet::B)),
+        vec![batch_id.to_string()],
+    ))
+}
+
+impl Database {
+    /// Commits the writes of the batch atomically, and returns the id of the batch.
+    /// The records of the batch are published first, all with the same creation time,
+    /// then a commit marker makes them visible at once. Removals are recorded as tombstones.
+    /// If the commit is interrupted before the marker is published, none of the writes is visible.
+    pub async fn commit(&self, batch: WriteBatch) -> Result<String, NostrDBError> {
+        // Nothing is published when a write is refused
+        for op in batch.ops.iter() {
+            self.ensure_writable(op.key())?;
+        }
+
+        let batch_id = new_batch_id();
+        let created_at = Timestamp::now();
+
+        // Whether each written key still holds a value after the batch
+        let mut written: HashMap<String, bool> = HashMap::new();
+
+        for (index, op) in batch.ops.into_iter().enumerate() {
+            let batch_tag = batch_tag(&batch_id, index);
+
+            let builder = match op {
+                BatchOp::Store { key, content } => {
+                    let builder = self.build_record(&key, &content, vec![batch_tag]).await?;
+                    written.insert(key, true);
+                    builder
+                }
+                BatchOp::Put { key, encode } => {
+                    let codec = self.codec_for(&key);
+                    let payload =
+                        encode(codec).map_err(|e| NostrDBError::CodecError(e.to_string()))?;
+                    let codec_tag = Tag::custom(TagKind::custom(CODEC_TAG), vec![codec.id()]);
+                    let builder = self
+                        .build_record(&key, &payload, vec![codec_tag, batch_tag])
+                        .await?;
+                    written.insert(key, true);
+                    builder
+                }
+                BatchOp::Event { key, encode } => {
+                    let codec = self.codec_for(&key);
+                    let payload =
+                        encode(codec).map_err(|e| NostrDBError::EventStreamError(e.to_string()))?;
+                    let codec_tag = Tag::custom(TagKind::custom(CODEC_TAG), vec![codec.id()]);
+                    let builder = self
+                        .build_record(&key, &payload, vec![codec_tag, batch_tag])
+                        .await?;
+                    written.insert(key, true);
+                    builder
+                }
+                BatchOp::Remove { key } => {
+                    let builder = self.tombstone(&key)?.tag(batch_tag);
+                    written.insert(key, false);
+                    builder
+                }
+            };
+
+            self.send_event(builder.custom_created_at(created_at))
+                .await?;
+        }
+
+        self.send_event(batch_marker(&batch_id)).await?;
+
+        self.update_index(written).await;
+
+        Ok(batch_id)
+    }
+
+    /// Builds the tombstone of the given key, removing all its previous values.
+    pub(super) fn tombstone(&self, key: &str) -> Result<EventBuilder, NostrDBError> {
+        Ok(EventBuilder::new(Kind::Custom(NOSTR_STORE_KIND), "")
+            .tag(self.d_tag(key))
+            .tag(Tag::custom(
+                TagKind::custom(TOMBSTONE_TAG),
+                Vec::<String>::new(),
+            )))
+    }
+
+    /// Returns the ids of the write batches, referenced by the given events, that have been committed.
+    pub(super) async fn committed_batches(
+        &self,
+        events: &Events,
+        options: &QueryOptions,
+    ) -> Result<HashSet<String>, NostrDBError> {
+        let batches: HashSet<String> = events
+            .iter()
+            .filter_map(|event| NostrRecord::from(event).batch)
+            .collect();
+
+        if batches.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let filter = Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_BATCH_KIND))
+            .authors(self.authors.iter().copied())
+            .custom_tags(SingleLetterTag::lowercase(Alphabet::B), batches);
+        let markers = self.fetch_events(filter, options).await?;
+
+        Ok(markers
+            .iter()
+            .flat_map(|marker| marker.tags.iter())
+            .filter(|tag| tag.single_letter_tag() == Some(SingleLetterTag::lowercase(Alphabet::B)))
+            .filter_map(|tag| tag.content())
+            .map(str::to_string)
+            .collect())
+    }
+}
@@ -0,0 +1,259 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use futures::stream::BoxStream;
+use nostr_sdk::{Keys, Timestamp};
+use nostr_sdk::prelude::IntoNostrSigner;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use super::{
+    ConsistencyReport, Database, NostrRecord, QueryOptions, RotationReport, StoreReceipt,
+    SyncReport, TypedRecord, Version, Versioned, WriteBatch,
+};
+use crate::{NostrDBError, Operation};
+
+/// A named namespace within a `Database`.
+/// The bucket name is mixed into the HMAC of its keys, so the keys of a bucket
+/// never collide with the keys of the database or of the other buckets.
+/// It's created with `Database::bucket`.
+pub struct Bucket {
+    name: String,
+    database: Database,
+}
+
+impl Bucket {
+    pub(super) fn new(name: String, database: Database) -> Self {
+        Self { name, database }
+    }
+
+    /// Returns the name of the bucket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the database scoped to this bucket, exposing its whole API.
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    /// Stores a new key-value pair in the bucket.
+    pub async fn store<T: Into<String>>(
+        &self,
+        key: T,
+        content: &str,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        self.database.store(key, content).await
+    }
+
+    /// Stores a new key-value pair in the bucket that expires after the given time-to-live.
+    pub async fn store_with_ttl<T: Into<String>>(
+        &self,
+        key: T,
+        content: &str,
+        ttl: Duration,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        self.database.store_with_ttl(key, content, ttl).await
+    }
+
+    /// Stores a serializable value associated with the given key in the bucket.
+    pub async fn put<K: Into<String>, T: Serialize>(
+        &self,
+        key: K,
+        value: &T,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        self.database.put(key, value).await
+    }
+
+    /// Removes all values associated with the given key from the bucket.
+    pub async fn remove<T: Into<String>>(&self, key: T) -> Result<(), NostrDBError> {
+        self.database.remove(key).await
+    }
+
+    /// Reads the last value associated with the given key from the bucket.
+    pub async fn read<T: Into<String>>(&self, key: T) -> Result<String, NostrDBError> {
+        self.database.read(key).await
+    }
+
+    /// Reads the value associated with the given key in the bucket at the given point in time.
+    pub async fn read_at<T: Into<String>>(
+        &self,
+        key: T,
+        timestamp: Timestamp,
+    ) -> Result<String, NostrDBError> {
+        self.database.read_at(key, timestamp).await
+    }
+
+    /// Reads the last value associated with the given key from the bucket and decodes it.
+    pub async fn get<K: Into<String>, T: DeserializeOwned>(
+        &self,
+        key: K,
+    ) -> Result<T, NostrDBError> {
+        self.database.get(key).await
+    }
+
+    /// Reads the history of values associated with the given key from the bucket.
+    pub async fn read_history<T: Into<String>>(
+        &self,
+        key: T,
+        options: QueryOptions,
+    ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
+        self.database.read_history(key, options).await
+    }
+
+    /// Streams the history of values associated with the given key from the bucket, one page at a time.
+    pub fn history_stream<T: Into<String>>(
+        &self,
+        key: T,
+        options: QueryOptions,
+    ) -> BoxStream<'_, Result<NostrRecord, NostrDBError>> {
+        self.database.history_stream(key, options)
+    }
+
+    /// Reads the history of values associated with the given key from the bucket and decodes each of them.
+    pub async fn get_history<K: Into<String>, T: DeserializeOwned>(
+        &self,
+        key: K,
+        options: QueryOptions,
+    ) -> Result<Vec<TypedRecord<T>>, NostrDBError> {
+        self.database.get_history(key, options).await
+    }
+
+    /// Reads the last value associated with the given key from the bucket, along with its version.
+    pub async fn read_versioned<T: Into<String>>(
+        &self,
+        key: T,
+    ) -> Result<Versioned<String>, NostrDBError> {
+        self.database.read_versioned(key).await
+    }
+
+    /// Stores a new value of the given key in the bucket, only if the key is still at the expected version.
+    pub async fn compare_and_swap<T: Into<String>>(
+        &self,
+        key: T,
+        expected: &Version,
+        content: &str,
+    ) -> Result<Version, NostrDBError> {
+        self.database.compare_and_swap(key, expected, content).await
+    }
+
+    /// Stores an event-operation in the bucket.
+    pub async fn store_event<I: Into<String>, O: Operation>(
+        &self,
+        key: I,
+        operation: O,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        self.database.store_event(key, operation).await
+    }
+
+    /// Reads the event-stream of the given key from the bucket, processed by the given operation.
+    pub async fn read_event<O: Operation>(
+        &self,
+        key: impl Into<String>,
+    ) -> Result<O::Value, NostrDBError> {
+        self.database.read_event::<O>(key).await
+    }
+
+    /// Commits the writes of the batch to the bucket atomically, and returns the id of the batch.
+    pub async fn commit(&self, batch: WriteBatch) -> Result<String, NostrDBError> {
+        self.database.commit(batch).await
+    }
+
+    /// Reads the event-stream of the given key from the bucket, as it was at the given point in time.
+    pub async fn read_event_at<O: Operation>(
+        &self,
+        key: impl Into<String>,
+        timestamp: Timestamp,
+    ) -> Result<O::Value, NostrDBError> {
+        self.database.read_event_at::<O>(key, timestamp).await
+    }
+
+    /// Watches the given key of the bucket for new values.
+    pub async fn watch<T: Into<String>>(
+        &self,
+        key: T,
+    ) -> Result<BoxStream<'_, Result<NostrRecord, NostrDBError>>, NostrDBError> {
+        self.database.watch(key).await
+    }
+
+    /// Watches the event-stream of the given key of the bucket, processed by the given operation.
+    pub async fn watch_event<O>(
+        &self,
+        key: impl Into<String>,
+    ) -> Result<BoxStream<'_, Result<O::Value, NostrDBError>>, NostrDBError>
+    where
+        O: Operation + 'static,
+        O::Value: Send,
+    {
+        self.database.watch_event::<O>(key).await
+    }
+
+    /// Reports the relays that are missing some of the events of the given key in the bucket.
+    pub async fn consistency_report<T: Into<String>>(
+        &self,
+        key: T,
+    ) -> Result<ConsistencyReport, NostrDBError> {
+        self.database.consistency_report(key).await
+    }
+
+    /// Republishes the events of the given key in the bucket to the relays missing them.
+    pub async fn repair<T: Into<String>>(&self, key: T) -> Result<SyncReport, NostrDBError> {
+        self.database.repair(key).await
+    }
+
+    /// Lists the keys stored in the bucket, sorted alphabetically.
+    pub async fn list_keys(&self) -> Result<Vec<String>, NostrDBError> {
+        self.database.list_keys().await
+    }
+
+    /// Removes all the keys stored in the bucket.
+    /// The keys are found through the bucket index, which can be recovered with `rebuild_index`.
+    pub async fn clear(&self) -> Result<(), NostrDBError> {
+        for key in self.database.list_keys().await? {
+            self.database.remove(key).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves the keys of the bucket to the same bucket of the identity of the given keys.
+    pub async fn rotate_to(&self, new_keys: Keys) -> Result<RotationReport, NostrDBError> {
+        self.database.rotate_to(new_keys).await
+    }
+
+    /// Moves the keys of the bucket, and the given keys, to the same bucket of the identity of
+    /// the given keys.
+    pub async fn rotate_to_with_keys<I, S>(
+        &self,
+        new_keys: Keys,
+        keys: I,
+    ) -> Result<RotationReport, NostrDBError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.database.rotate_to_with_keys(new_keys, keys).await
+    }
+
+    /// Moves the keys of the bucket to the same bucket of the identity of the given signer.
+    pub async fn rotate_to_signer<S>(&self, signer: S) -> Result<RotationReport, NostrDBError>
+    where
+        S: IntoNostrSigner,
+    {
+        self.database.rotate_to_signer(signer).await
+    }
+
+    /// Moves the keys of the bucket, and the given keys, to the same bucket of the identity of
+    /// the given signer.
+    pub async fn rotate_to_signer_with_keys<S, I, K>(
+        &self,
+        signer: S,
+        keys: I,
+    ) -> Result<RotationReport, NostrDBError>
+    where
+        S: IntoNostrSigner,
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        self.database.rotate_to_signer_with_keys(signer, keys).await
+    }
+}
@@ -1,18 +1,85 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::cache::{DEFAULT_CACHE_OVERLAP, LocalCache};
+use super::chunk::DEFAULT_CHUNK_SIZE;
 use super::core::Database;
+#[cfg(feature = "file-cache")]
+use super::file_cache::FileDatabase;
+use super::index::KeyIndex;
+use super::keys::{KeyScheme, Keyring};
+use super::outbox::Outbox;
+use super::query::DEFAULT_TIMEOUT;
+use super::quorum::WriteQuorum;
+use crate::codec::CodecKind;
+use crate::compression::CompressionKind;
 use crate::error::NostrDBError;
+use nostr_sdk::prelude::{
+    IntoNostrDatabase, IntoNostrSigner, NostrDatabase, NostrSigner, PublicKey, ReqExitPolicy,
+};
 use nostr_sdk::{Keys, RelayOptions, RelayPool};
+use tokio::sync::Mutex;
 
-/// Constructs a Nostr database with a relay pool and keys.
+/// Constructs a Nostr database with a relay pool and a signer.
 pub struct DatabaseBuilder {
-    keys: Keys,
+    signer: Arc<dyn NostrSigner>,
+    /// The secret key of the identity, when the signer is local keys.
+    secret_key: Option<[u8; 32]>,
+    key_scheme: Option<KeyScheme>,
     relays: Vec<String>,
+    codec: CodecKind,
+    key_codecs: HashMap<String, CodecKind>,
+    compression: Option<CompressionKind>,
+    compression_threshold: usize,
+    chunk_size: usize,
+    timeout: Duration,
+    exit_policy: ReqExitPolicy,
+    write_quorum: WriteQuorum,
+    cache_database: Option<Arc<dyn NostrDatabase>>,
+    #[cfg(feature = "file-cache")]
+    cache_path: Option<PathBuf>,
+    cache_staleness: Duration,
+    cache_overlap: Duration,
+    outbox_path: Option<PathBuf>,
+    outbox_flush_interval: Option<Duration>,
 }
 
 impl DatabaseBuilder {
+    /// Creates a builder for the given keys, using the identity key scheme by default.
     pub fn new(keys: Keys) -> Self {
+        let secret_key = Some(keys.secret_key().to_secret_bytes());
         Self {
-            keys,
+            secret_key,
+            ..Self::from_signer(keys)
+        }
+    }
+
+    /// Creates a builder signing events with the given signer, such as a NIP-46 remote signer,
+    /// so that the secret key never has to be held by the database.
+    /// It uses the derived key scheme, the only one not requiring the secret key.
+    pub fn from_signer<S: IntoNostrSigner>(signer: S) -> Self {
+        Self {
+            signer: signer.into_nostr_signer(),
+            secret_key: None,
+            key_scheme: None,
             relays: vec![],
+            codec: CodecKind::default(),
+            key_codecs: HashMap::new(),
+            compression: None,
+            compression_threshold: 0,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            timeout: DEFAULT_TIMEOUT,
+            exit_policy: ReqExitPolicy::default(),
+            write_quorum: WriteQuorum::default(),
+            cache_database: None,
+            #[cfg(feature = "file-cache")]
+            cache_path: None,
+            cache_staleness: Duration::ZERO,
+            cache_overlap: DEFAULT_CACHE_OVERLAP,
+            outbox_path: None,
+            outbox_flush_interval: None,
         }
     }
 
@@ -32,6 +99,105 @@ impl DatabaseBuilder {
         self
     }
 
+    /// Sets the codec used to encode values stored with `put`.
+    pub fn with_codec(mut self, codec: CodecKind) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets the codec used to encode values stored with `put` under the given key,
+    /// overriding the database codec.
+    pub fn with_key_codec<T: Into<String>>(mut self, key: T, codec: CodecKind) -> Self {
+        self.key_codecs.insert(key.into(), codec);
+        self
+    }
+
+    /// Compresses values with the given algorithm before they are encrypted,
+    /// when their size in bytes reaches the given threshold.
+    pub fn with_compression(mut self, compression: CompressionKind, threshold: usize) -> Self {
+        self.compression = Some(compression);
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a value stored in a single event.
+    /// Larger values are split into chunk events referenced by a manifest event.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Sets the time given to the relays to answer a request.
+    /// Relays that don't answer in time are skipped, and reads fail when none of them answered.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets when the relays stop sending the events of a request.
+    pub fn with_exit_policy(mut self, exit_policy: ReqExitPolicy) -> Self {
+        self.exit_policy = exit_policy;
+        self
+    }
+
+    /// Sets the number of relays that must accept an event for a write to succeed.
+    pub fn with_write_quorum(mut self, write_quorum: WriteQuorum) -> Self {
+        self.write_quorum = write_quorum;
+        self
+    }
+
+    /// Caches the events fetched from the relays or published in the given database, such as
+    /// the LMDB or SQLite backend of nostr-sdk opened in a directory. Reads only fetch the events
+    /// created since their key was last synced, and are served from the cache alone within its
+    /// staleness. The sync time of each key is saved in the database along with the events.
+    pub fn with_local_cache<D: IntoNostrDatabase>(mut self, database: D) -> Self {
+        self.cache_database = Some(database.into_nostr_database());
+        self
+    }
+
+    /// Caches the events fetched from the relays or published in a cache persisted in the given
+    /// directory, as with `with_local_cache`. Requires the `file-cache` feature.
+    #[cfg(feature = "file-cache")]
+    pub fn with_local_cache_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.cache_path = Some(path.into());
+        self
+    }
+
+    /// Sets for how long reads are served from the local cache alone, after its events of
+    /// the key have been synced with the relays. By default every read checks the relays.
+    pub fn with_cache_staleness(mut self, staleness: Duration) -> Self {
+        self.cache_staleness = staleness;
+        self
+    }
+
+    /// Sets how far back before the last sync of a key its events are fetched again, to cache
+    /// the events that relays received late, such as the writes of a device that was offline.
+    /// Defaults to `DEFAULT_CACHE_OVERLAP`.
+    pub fn with_cache_overlap(mut self, overlap: Duration) -> Self {
+        self.cache_overlap = overlap;
+        self
+    }
+
+    /// Queues the events that no relay accepts in a durable outbox persisted in the given directory,
+    /// instead of failing the write. Queued events are published with `Database::flush_outbox`.
+    pub fn with_outbox<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.outbox_path = Some(path.into());
+        self
+    }
+
+    /// Flushes the outbox at the given interval in a background task.
+    pub fn with_outbox_flush_interval(mut self, interval: Duration) -> Self {
+        self.outbox_flush_interval = Some(interval);
+        self
+    }
+
+    /// Sets how the keys hashing the tags and encrypting the values are obtained.
+    /// Data stored with one scheme is not found by databases using the other.
+    pub fn with_key_scheme(mut self, key_scheme: KeyScheme) -> Self {
+        self.key_scheme = Some(key_scheme);
+        self
+    }
+
     /// Creates a new Database instance with the provided keys and relays.
     pub async fn build(self) -> Result<Database, NostrDBError> {
         if self.relays.is_empty() {
@@ -51,9 +217,64 @@ impl DatabaseBuilder {
 
         relay_pool.connect().await;
 
-        Ok(Database {
-            keys: self.keys,
+        let key_scheme = self.key_scheme.unwrap_or(match self.secret_key {
+            Some(_) => KeyScheme::Identity,
+            None => KeyScheme::Derived,
+        });
+        let public_key: PublicKey = self
+            .signer
+            .get_public_key()
+            .await
+            .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+
+        #[cfg(feature = "file-cache")]
+        let cache_database = match self.cache_path {
+            Some(path) => Some(Arc::new(FileDatabase::open(path).await?) as Arc<dyn NostrDatabase>),
+            None => self.cache_database,
+        };
+        #[cfg(not(feature = "file-cache"))]
+        let cache_database = self.cache_database;
+        let cache = cache_database
+            .map(|database| LocalCache::open(database, self.cache_staleness, self.cache_overlap));
+
+        let outbox = match self.outbox_path {
+            Some(path) => Some(Arc::new(Outbox::open(path).await?)),
+            None => None,
+        };
+        if let (Some(outbox), Some(interval)) = (&outbox, self.outbox_flush_interval) {
+            Outbox::spawn_flush(outbox, relay_pool.clone(), interval);
+        }
+
+        let mut database = Database {
+            signer: self.signer,
+            public_key,
+            keyring: Keyring::identity(self.secret_key.unwrap_or_default()),
+            authors: vec![public_key],
+            access: None,
             relay_pool,
-        })
+            codec: self.codec,
+            key_codecs: self.key_codecs,
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            chunk_size: self.chunk_size,
+            key_index: Mutex::new(KeyIndex::default()),
+            timeout: self.timeout,
+            exit_policy: self.exit_policy,
+            write_quorum: self.write_quorum,
+            cache: cache.map(Arc::new),
+            outbox,
+        };
+        match (key_scheme, self.secret_key) {
+            (KeyScheme::Identity, Some(_)) => {}
+            (KeyScheme::Identity, None) => {
+                return Err(NostrDBError::DatabaseError(
+                    "The identity key scheme requires local keys".to_string(),
+                ));
+            }
+            (KeyScheme::Derived, _) => {
+                database.keyring = Keyring::derived(database.load_storage_key().await?);
+            }
+        }
+        Ok(database)
     }
 }
@@ -0,0 +1,297 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use nostr_sdk::prelude::*;
+use tokio::sync::Mutex;
+
+use super::QueryOptions;
+use super::core::Database;
+use crate::NostrDBError;
+
+/// Kind of the events holding the sync marks of a cache, one for each key. They are only saved
+/// in the cache, never published, and signed with a well-known key, so that each replaces the
+/// previous mark of its key.
+const NOSTR_STORE_CACHE_MARKS_KIND: u16 = 39219;
+
+/// Secret key signing the marks of a cache. It guards nothing: the marks never leave the cache.
+const CACHE_MARKS_SECRET_KEY: [u8; 32] = [1; 32];
+
+/// How far back before the last sync of a key its events are fetched again by default.
+pub const DEFAULT_CACHE_OVERLAP: Duration = Duration::from_secs(600);
+
+/// How far the cache is synced with the relays for a key.
+#[derive(Debug, Clone, Copy, Default)]
+struct CacheMark {
+    /// When every event of the key was last fetched, if ever.
+    /// Relays may receive events created before it afterwards, from writers that were offline,
+    /// so the next fetch starts the overlap of the cache before it.
+    synced_at: Option<Timestamp>,
+    /// When the key was last synced with the relays, in this session.
+    synced: Option<Instant>,
+}
+
+/// The local cache of a database: the events fetched from the relays or published, held by a
+/// nostr-sdk database, and the time the events of each key were last synced with the relays.
+/// The events of a key are its events of one kind, `d` tag and author, so that the marks don't
+/// depend on the rest of the filters, and the marks of the other keys are left as they are.
+#[derive(Debug)]
+pub(crate) struct LocalCache {
+    database: Arc<dyn NostrDatabase>,
+    staleness: Duration,
+    overlap: Duration,
+    /// The marks looked up or set in this session, by key.
+    marks: Mutex<HashMap<String, CacheMark>>,
+}
+
+impl LocalCache {
+    /// Opens the cache held by the given database. The marks saved in it are read as needed.
+    pub fn open(database: Arc<dyn NostrDatabase>, staleness: Duration, overlap: Duration) -> Self {
+        Self {
+            database,
+            staleness,
+            overlap,
+            marks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Saves the given events in the cache.
+    pub async fn save(&self, events: impl IntoIterator<Item = &Event>) -> Result<(), NostrDBError> {
+        for event in events {
+            self.database.save_event(event).await.map_err(cache_error)?;
+        }
+        Ok(())
+    }
+
+    pub async fn query(&self, filter: Filter) -> Result<Events, NostrDBError> {
+        query_database(self.database.as_ref(), filter)
+            .await
+            .map_err(cache_error)
+    }
+
+    /// Returns the mark of the given key, reading it from the cache the first time.
+    async fn mark(&self, key: &str) -> Result<CacheMark, NostrDBError> {
+        if let Some(mark) = self.marks.lock().await.get(key) {
+            return Ok(*mark);
+        }
+
+        let saved = self
+            .database
+            .query(marks_filter(key))
+            .await
+            .map_err(cache_error)?;
+        let mark = CacheMark {
+            synced_at: saved
+                .first()
+                .and_then(|event| event.content.parse::<u64>().ok())
+                .map(Timestamp::from),
+            synced: None,
+        };
+        Ok(*self
+            .marks
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_insert(mark))
+    }
+
+    fn is_fresh(&self, mark: &CacheMark) -> bool {
+        mark.synced
+            .is_some_and(|synced| synced.elapsed() < self.staleness)
+    }
+
+    /// Returns the time from which the events of a key synced at the given time are fetched.
+    fn resume_from(&self, synced_at: Timestamp) -> Timestamp {
+        Timestamp::from(synced_at.as_u64().saturating_sub(self.overlap.as_secs()))
+    }
+
+    /// Records that every event of the key was fetched at the given time.
+    async fn set_mark(&self, key: &str, synced_at: Timestamp) -> Result<(), NostrDBError> {
+        self.marks.lock().await.insert(
+            key.to_string(),
+            CacheMark {
+                synced_at: Some(synced_at),
+                synced: Some(Instant::now()),
+            },
+        );
+
+        // The mark replaces the previous one of the key; marks set in the same second are equal
+        let event = EventBuilder::new(
+            Kind::Custom(NOSTR_STORE_CACHE_MARKS_KIND),
+            synced_at.as_u64().to_string(),
+        )
+        .tag(Tag::identifier(key))
+        .custom_created_at(synced_at)
+        .sign_with_keys(&marks_keys())
+        .map_err(cache_error)?;
+        self.database
+            .save_event(&event)
+            .await
+            .map_err(cache_error)?;
+        Ok(())
+    }
+}
+
+impl Database {
+    /// Fetches the events matching the filter through the local cache.
+    /// Filters whose keys were all synced with the relays within the staleness of the cache are
+    /// served from it. Otherwise only the events created since the overlap of the cache before
+    /// the earliest last sync of their keys are fetched, along with the deletion requests
+    /// published since, and the cache answers the filter.
+    pub(super) async fn fetch_cached(
+        &self,
+        cache: &LocalCache,
+        filter: Filter,
+        options: &QueryOptions,
+    ) -> Result<Events, NostrDBError> {
+        // Events never change, so events requested by id are only fetched once
+        if let Some(ids) = &filter.ids {
+            let cached = cache.query(filter.clone()).await?;
+            if cached.len() == ids.len() {
+                return Ok(cached);
+            }
+        }
+
+        // Only the events of a filter selecting whole keys are marked, the others are fetched
+        let keys = cache_keys(&filter);
+        if keys.is_empty() {
+            let events = self.fetch_remote(filter.clone(), options).await?;
+            cache.save(events.iter()).await?;
+            return Ok(events);
+        }
+        let mut marks = Vec::with_capacity(keys.len());
+        for key in keys.iter() {
+            marks.push(cache.mark(key).await?);
+        }
+        if marks.iter().all(|mark| cache.is_fresh(mark)) {
+            return cache.query(filter).await;
+        }
+
+        // Events are fetched again from the overlap before the last sync, to catch the ones
+        // relays received since, even if they were created before it
+        let resume_from = marks
+            .iter()
+            .map(|mark| mark.synced_at.map(|synced_at| cache.resume_from(synced_at)))
+            .collect::<Option<Vec<_>>>()
+            .and_then(|resumes| resumes.into_iter().min());
+        // The cache already holds every event created before the bounds of the filter
+        if resume_from
+            .is_some_and(|resume_from| filter.until.is_some_and(|until| until < resume_from))
+        {
+            return cache.query(filter).await;
+        }
+
+        let since = match (filter.since, resume_from) {
+            (Some(since), Some(resume_from)) => Some(since.max(resume_from)),
+            (since, resume_from) => since.or(resume_from),
+        };
+        let remote = Filter {
+            since,
+            ..filter.clone()
+        };
+        let deletions = match (resume_from, &filter.authors) {
+            (Some(resume_from), Some(authors)) => Some(
+                Filter::new()
+                    .kind(Kind::EventDeletion)
+                    .authors(authors.iter().copied())
+                    .since(resume_from),
+            ),
+            _ => None,
+        };
+
+        let synced_at = Timestamp::now();
+        let (events, deleted) =
+            future::try_join(self.fetch_remote(remote.clone(), options), async {
+                match deletions {
+                    Some(deletions) => self.fetch_remote(deletions, options).await.map(Some),
+                    None => Ok(None),
+                }
+            })
+            .await?;
+
+        cache.save(events.iter()).await?;
+        if let Some(deleted) = &deleted {
+            cache.save(deleted.iter()).await?;
+        }
+
+        // The fetch covers every event of the keys since the last sync
+        let complete = since == resume_from
+            && remote.until.is_none()
+            && remote.limit.is_none_or(|limit| events.len() < limit);
+        if complete {
+            for key in keys.iter() {
+                cache.set_mark(key, synced_at).await?;
+            }
+        }
+
+        cache.query(filter).await
+    }
+}
+
+fn cache_error<E: std::fmt::Display>(e: E) -> NostrDBError {
+    NostrDBError::DatabaseError(format!("Local cache error: {}", e))
+}
+
+/// Queries the given database for the events matching the filter.
+/// Some databases, like `MemoryDatabase`, only match the first identifier of a filter of a single
+/// kind and author, so filters of several identifiers are queried one identifier at a time.
+pub(crate) async fn query_database(
+    database: &dyn NostrDatabase,
+    filter: Filter,
+) -> Result<Events, DatabaseError> {
+    let d = SingleLetterTag::lowercase(Alphabet::D);
+    let identifiers = match filter.generic_tags.get(&d) {
+        Some(identifiers) if identifiers.len() > 1 => identifiers.clone(),
+        _ => return database.query(filter).await,
+    };
+
+    let mut events = Events::new(&filter);
+    for identifier in identifiers {
+        let mut single = filter.clone();
+        single.generic_tags.insert(d, BTreeSet::from([identifier]));
+        events = events.merge(database.query(single).await?);
+    }
+    Ok(events)
+}
+
+/// Returns the keys whose events the filter selects: one for each of its kinds, `d` tags and
+/// authors. Filters not restricted to all three select no whole key.
+fn cache_keys(filter: &Filter) -> Vec<String> {
+    let identifiers = filter
+        .generic_tags
+        .get(&SingleLetterTag::lowercase(Alphabet::D));
+    let (Some(kinds), Some(identifiers), Some(authors)) =
+        (&filter.kinds, identifiers, &filter.authors)
+    else {
+        return Vec::new();
+    };
+
+    let mut keys = Vec::new();
+    for kind in kinds {
+        for identifier in identifiers {
+            for author in authors {
+                keys.push(format!(
+                    "{}:{}:{}",
+                    kind.as_u16(),
+                    identifier,
+                    author.to_hex()
+                ));
+            }
+        }
+    }
+    keys
+}
+
+/// Returns the keys signing the marks of a cache.
+fn marks_keys() -> Keys {
+    Keys::new(SecretKey::from_slice(&CACHE_MARKS_SECRET_KEY).expect("valid secret key"))
+}
+
+/// Returns the filter of the event holding the mark of the given key.
+fn marks_filter(key: &str) -> Filter {
+    Filter::new()
+        .kind(Kind::Custom(NOSTR_STORE_CACHE_MARKS_KIND))
+        .author(marks_keys().public_key)
+        .identifier(key)
+}
@@ -0,0 +1,378 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use futures::stream::{self, BoxStream, StreamExt};
+use nostr_sdk::prelude::*;
+
+use super::core::{
+    Database, NOSTR_STORE_AGGREGATE_KIND, NOSTR_STORE_BATCH_KIND, NOSTR_STORE_INDEX_KIND,
+    NOSTR_STORE_KIND,
+};
+use super::record::aggregate_records;
+use super::watch::{BatchGate, Subscription};
+use super::{NostrRecord, QueryOptions};
+use crate::NostrDBError;
+
+/// What a change did to its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// A value was stored.
+    Put,
+    /// The values of the key were removed.
+    Remove,
+    /// The records of the key were aggregated into a single event.
+    Aggregate,
+}
+
+/// A change made to a key of the identity, yielded by `Database::changes`.
+#[derive(Debug, Clone)]
+pub struct Change {
+    /// The name of the key, if it is listed in the index or among the given keys.
+    /// The index lists a new key only once its first record is published, so that record is
+    /// yielded without a name unless the key was given to `Database::changes_with_keys`.
+    pub key: Option<String>,
+    /// The value of the `d` tag of the key, identifying it even when its name is unknown.
+    pub tag: String,
+    pub kind: ChangeKind,
+    /// The decrypted record stored, the latest record of an aggregate, or the tombstone of a removal.
+    pub record: NostrRecord,
+    /// The timestamp to pass to `Database::changes` to resume the feed after this change.
+    /// The changes made in the same second as this one are yielded again.
+    pub cursor: Timestamp,
+}
+
+/// Number of events requested from the relays for each page of the backlog of a change feed.
+pub const CHANGES_PAGE_SIZE: usize = 500;
+
+/// Walks the events stored within a time range from the oldest to the newest, one time window
+/// at a time. Relays return the newest events of a filter first, so a window holding a full page
+/// is halved until the page is complete, and the next window starts right after it, twice as
+/// long, so that a few dense seconds don't slow down the walk of the rest of the range.
+pub(super) struct BacklogPages {
+    filter: Filter,
+    /// The start of the next window, or `None` once the range is walked.
+    since: Option<Timestamp>,
+    until: Timestamp,
+    /// The number of seconds after the start of a window that it covers.
+    window: u64,
+    /// The options the events are fetched with, reading them as they were stored by default.
+    options: QueryOptions,
+}
+
+impl BacklogPages {
+    pub(super) fn new(filter: Filter, since: Timestamp, until: Timestamp) -> Self {
+        let mut pages = Self {
+            filter,
+            since: None,
+            until,
+            window: 0,
+            options: QueryOptions::stored(),
+        };
+        pages.extend(since, until);
+        pages
+    }
+
+    /// Fetches the events with the timeout, exit policy and consistency of the given options.
+    pub(super) fn with_options(mut self, options: &QueryOptions) -> Self {
+        self.options = QueryOptions {
+            decrypt: false,
+            ..options.clone()
+        };
+        self
+    }
+
+    /// Walks the events stored within the given range next.
+    fn extend(&mut self, since: Timestamp, until: Timestamp) {
+        self.since = (since <= until).then_some(since);
+        self.until = until;
+        self.window = until.as_u64().saturating_sub(since.as_u64());
+    }
+
+    /// Fetches the events of the next window, from the oldest to the newest, or returns `None`
+    /// once the range is walked.
+    pub(super) async fn next(
+        &mut self,
+        database: &Database,
+    ) -> Result<Option<Vec<Event>>, NostrDBError> {
+        let options = self.options.clone();
+        self.next_with(|filter| database.fetch_events(filter, &options))
+            .await
+    }
+
+    /// Fetches the events of the next window from a single relay, like `next`.
+    pub(super) async fn next_from(
+        &mut self,
+        relay: &Relay,
+        timeout: Duration,
+        policy: ReqExitPolicy,
+    ) -> Result<Option<Vec<Event>>, NostrDBError> {
+        self.next_with(|filter| async move {
+            relay
+                .fetch_events(filter, timeout, policy)
+                .await
+                .map_err(|e| NostrDBError::NostrError(e.to_string()))
+        })
+        .await
+    }
+
+    /// Fetches the events of the next window with the given fetch, from the oldest to the newest.
+    async fn next_with<F, Fut>(&mut self, mut fetch: F) -> Result<Option<Vec<Event>>, NostrDBError>
+    where
+        F: FnMut(Filter) -> Fut,
+        Fut: Future<Output = Result<Events, NostrDBError>>,
+    {
+        let Some(since) = self.since else {
+            return Ok(None);
+        };
+
+        loop {
+            let end = since
+                .as_u64()
+                .saturating_add(self.window)
+                .min(self.until.as_u64());
+            let mut filter = self.filter.clone().since(since).until(Timestamp::from(end));
+            // The events of a single second are read at once, however many they are
+            let paged = end > since.as_u64();
+            if paged {
+                filter = filter.limit(CHANGES_PAGE_SIZE);
+            }
+
+            let events = fetch(filter).await?;
+            if paged && events.len() >= CHANGES_PAGE_SIZE {
+                self.window = (end - since.as_u64()) / 2;
+                continue;
+            }
+
+            self.since = (end < self.until.as_u64()).then(|| Timestamp::from(end + 1));
+            self.window = self.window.saturating_mul(2).max(1);
+            return Ok(Some(events.into_iter().rev().collect()));
+        }
+    }
+}
+
+/// Follows the nostrstore events of the identity: the ones stored since a timestamp, from the
+/// oldest to the newest, one page at a time, then the new ones as the relays send them.
+struct ChangeFeed {
+    pages: BacklogPages,
+    backlog: VecDeque<Event>,
+    /// The subscription to the new events, opened once the backlog is walked.
+    subscription: Option<Subscription>,
+    /// The ids of the backlog events stored in the last second walked, which the relays send
+    /// again to the subscription.
+    latest: HashSet<EventId>,
+    gate: BatchGate,
+    ready: VecDeque<Event>,
+    /// The names of the known keys, by the value of their `d` tag.
+    names: HashMap<String, String>,
+    /// The cursor of the last change yielded, or the start of the feed.
+    cursor: Timestamp,
+}
+
+impl ChangeFeed {
+    /// Returns the next visible event, holding back the records of uncommitted write batches.
+    async fn next(&mut self, database: &Database) -> Option<Result<Event, NostrDBError>> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return Some(Ok(event));
+            }
+            let event = match self.next_event(database).await? {
+                Ok(event) => event,
+                Err(NostrDBError::SubscriptionLagged { skipped, .. }) => {
+                    return Some(Err(NostrDBError::SubscriptionLagged {
+                        skipped,
+                        cursor: Some(self.cursor),
+                    }));
+                }
+                Err(e) => return Some(Err(e)),
+            };
+            self.ready.extend(self.gate.admit(event));
+        }
+    }
+
+    /// Returns the next event of the backlog, or of the subscription once the backlog is walked.
+    /// The events stored while the backlog was walked are walked too, until it reaches the
+    /// current second, so that they are returned in order.
+    async fn next_event(&mut self, database: &Database) -> Option<Result<Event, NostrDBError>> {
+        loop {
+            if let Some(event) = self.backlog.pop_front() {
+                if event.created_at == self.pages.until {
+                    self.latest.insert(event.id);
+                }
+                return Some(Ok(event));
+            }
+
+            if self.subscription.is_none() {
+                match self.pages.next(database).await {
+                    Ok(Some(events)) => {
+                        self.backlog.extend(events);
+                        continue;
+                    }
+                    Ok(None) => {}
+                    Err(e) => return Some(Err(e)),
+                }
+
+                let walked = self.pages.until;
+                let now = Timestamp::now();
+                if now > walked {
+                    self.pages.extend(Timestamp::from(walked.as_u64() + 1), now);
+                    self.latest.clear();
+                    continue;
+                }
+
+                let filter = self.pages.filter.clone().since(walked);
+                let seen = std::mem::take(&mut self.latest);
+                match Subscription::open(&database.relay_pool, vec![filter], seen).await {
+                    Ok(subscription) => self.subscription = Some(subscription),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+
+            return self.subscription.as_mut()?.next().await;
+        }
+    }
+}
+
+impl Database {
+    /// Follows every change made by the identity, or by the writers of a shared store, since the
+    /// given timestamp, across all keys.
+    /// The stream yields the changes already stored, from the oldest to the newest, then the new
+    /// ones as the relays send them. Key names are resolved from the index, as it is updated.
+    /// The changes already stored are fetched `CHANGES_PAGE_SIZE` events at a time, and the
+    /// relays are subscribed to once they are all yielded, up to the current second.
+    /// When changes were lost because the stream was not polled fast enough, it yields
+    /// `SubscriptionLagged` with the cursor of the last change yielded, to resume the feed from.
+    /// The subscription is closed when the stream is dropped.
+    pub async fn changes(
+        &self,
+        since: Timestamp,
+    ) -> Result<BoxStream<'_, Result<Change, NostrDBError>>, NostrDBError> {
+        self.changes_with_keys(since, Vec::<String>::new()).await
+    }
+
+    /// Follows every change made by the identity since the given timestamp, like `changes`,
+    /// also resolving the names of the given keys, for keys missing from the index.
+    pub async fn changes_with_keys<I, S>(
+        &self,
+        since: Timestamp,
+        keys: I,
+    ) -> Result<BoxStream<'_, Result<Change, NostrDBError>>, NostrDBError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut names = HashMap::new();
+        let indexed = self.list_keys().await?;
+        for key in indexed.into_iter().chain(keys.into_iter().map(Into::into)) {
+            names.insert(self.tag_hash(&key), key);
+        }
+
+        let now = Timestamp::now();
+        let filter = Filter::new().authors(self.authors.iter().copied()).kinds([
+            Kind::Custom(NOSTR_STORE_KIND),
+            Kind::Custom(NOSTR_STORE_AGGREGATE_KIND),
+            Kind::Custom(NOSTR_STORE_INDEX_KIND),
+            Kind::Custom(NOSTR_STORE_BATCH_KIND),
+        ]);
+
+        let feed = ChangeFeed {
+            pages: BacklogPages::new(filter, since, now),
+            backlog: VecDeque::new(),
+            subscription: None,
+            latest: HashSet::new(),
+            gate: BatchGate::default(),
+            ready: VecDeque::new(),
+            names,
+            cursor: since,
+        };
+
+        Ok(stream::unfold(feed, move |mut feed| async move {
+            loop {
+                let event = match feed.next(self).await? {
+                    Ok(event) => event,
+                    Err(e) => return Some((Err(e), feed)),
+                };
+                match self.change(&mut feed.names, &event).await {
+                    Ok(Some(change)) => {
+                        feed.cursor = change.cursor;
+                        return Some((Ok(change), feed));
+                    }
+                    Ok(None) => {}
+                    Err(e) => return Some((Err(e), feed)),
+                }
+            }
+        })
+        .boxed())
+    }
+
+    /// Describes the change made by a visible event, if it changed a key.
+    /// Index events update the names of the keys instead.
+    async fn change(
+        &self,
+        names: &mut HashMap<String, String>,
+        event: &Event,
+    ) -> Result<Option<Change>, NostrDBError> {
+        if self.is_index(event)? {
+            for key in self.open_index(event).await? {
+                names.insert(self.tag_hash(&key), key);
+            }
+            return Ok(None);
+        }
+
+        let now = Timestamp::now().as_u64();
+        let (kind, record) = if event.kind == Kind::Custom(NOSTR_STORE_KIND) {
+            let record = NostrRecord::from(event);
+            if record.is_expired(now) {
+                return Ok(None);
+            }
+            let kind = if record.tombstone {
+                ChangeKind::Remove
+            } else {
+                ChangeKind::Put
+            };
+            (kind, record)
+        } else if event.kind == Kind::Custom(NOSTR_STORE_AGGREGATE_KIND) {
+            let records: BTreeSet<NostrRecord> = aggregate_records(event)?.into_iter().collect();
+            match records
+                .into_iter()
+                .rev()
+                .find(|record| !record.is_expired(now))
+            {
+                Some(record) if record.tombstone => (ChangeKind::Remove, record),
+                Some(record) => (ChangeKind::Aggregate, record),
+                // `remove` resets the aggregate of the key to empty
+                None => {
+                    let mut tombstone = NostrRecord::new(
+                        event.created_at.as_u64(),
+                        String::new(),
+                        event.id.to_string(),
+                    );
+                    tombstone.tombstone = true;
+                    (ChangeKind::Remove, tombstone)
+                }
+            }
+        } else {
+            return Ok(None);
+        };
+
+        let Some(tag) = event.tags.identifier() else {
+            return Ok(None);
+        };
+        // The records of unknown keys are only kept when the owner of the store wrote them
+        if let Some(access) = &self.access {
+            let allowed = match names.get(tag) {
+                Some(key) => access.allows(&event.pubkey, key),
+                None => event.pubkey == access.owner,
+            };
+            if !allowed {
+                return Ok(None);
+            }
+        }
+        Ok(Some(Change {
+            key: names.get(tag).cloned(),
+            tag: tag.to_string(),
+            kind,
+            record: self.open_record(&event.pubkey, record).await?,
+            cursor: event.created_at,
+        }))
+    }
+}
@@ -0,0 +1,61 @@
+use nostr_sdk::prelude::hex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Name of the tags referencing the chunk events of a chunked value.
+pub(crate) const CHUNK_TAG: &str = "chunk";
+
+/// The default maximum size in bytes of a value stored in a single event.
+/// It's kept well below the NIP-44 plaintext limit so that encrypted events fit common relay limits.
+pub const DEFAULT_CHUNK_SIZE: usize = 32 * 1024;
+
+/// The content of the manifest event of a chunked value.
+/// The ids of the chunk events are stored in the `chunk` tags of the manifest event, in order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ChunkManifest {
+    /// Hex encoded SHA-256 hash of the reassembled value.
+    pub hash: String,
+    /// Length in bytes of the reassembled value.
+    pub length: usize,
+}
+
+impl ChunkManifest {
+    pub fn new(content: &str) -> Self {
+        Self {
+            hash: hash_content(content),
+            length: content.len(),
+        }
+    }
+
+    /// Checks that the reassembled content matches the manifest.
+    pub fn verify(&self, content: &str) -> bool {
+        content.len() == self.length && hash_content(content) == self.hash
+    }
+}
+
+fn hash_content(content: &str) -> String {
+    hex::encode(Sha256::digest(content.as_bytes()))
+}
+
+/// Splits the content into chunks of at most `size` bytes, without splitting characters.
+pub(crate) fn split_chunks(content: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = content;
+
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A size smaller than a single character still has to make progress
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+
+        let (chunk, tail) = rest.split_at(end);
+        chunks.push(chunk);
+        rest = tail;
+    }
+
+    chunks
+}
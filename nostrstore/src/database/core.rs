@@ -1,131 +1,459 @@
-use std::collections::BTreeSet;
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use hmac::Hmac;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use futures::{TryStreamExt, future};
 use nostr_sdk::prelude::*;
 use nostr_sdk::{Keys, RelayPool};
-use tracing::info;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::Mutex;
 
+use super::acl::AccessControl;
+use super::batch::apply_tombstones;
+use super::cache::LocalCache;
+use super::chunk::{CHUNK_TAG, ChunkManifest, split_chunks};
+use super::index::KeyIndex;
+use super::keys::{Keyring, hmac};
+use super::outbox::Outbox;
 use super::query::QueryOptions;
-use super::{DatabaseBuilder, NostrRecord};
+use super::quorum::{ReadConsistency, RelayFetch, StoreReceipt, WriteQuorum, relay_names};
+use super::record::{CODEC_TAG, COMPRESSION_TAG, aggregate_records};
+use super::version::heads;
+use super::{Bucket, DatabaseBuilder, NostrRecord, TypedRecord};
+use crate::codec::CodecKind;
+use crate::compression::CompressionKind;
 use crate::{NostrDBError, Operation};
 
-const NOSTR_STORE_KIND: u16 = 9215;
-const NOSTR_STORE_AGGREGATE_KIND: u16 = 39215;
+pub(super) const NOSTR_STORE_KIND: u16 = 9215;
+pub(super) const NOSTR_STORE_AGGREGATE_KIND: u16 = 39215;
+pub(super) const NOSTR_STORE_CHUNK_KIND: u16 = 9216;
+pub(super) const NOSTR_STORE_INDEX_KIND: u16 = 39216;
+pub(super) const NOSTR_STORE_BATCH_KIND: u16 = 9217;
+pub(super) const NOSTR_STORE_KEY_KIND: u16 = 39217;
+pub(super) const NOSTR_STORE_ENVELOPE_KIND: u16 = 9218;
+pub(super) const NOSTR_STORE_MEMBERS_KIND: u16 = 39218;
 
-/// Represents a Nostr database with a relay pool and keys.
+/// Represents a Nostr database with a relay pool and a signer.
 /// It provides methods to send, store, remove, and read events.
 /// It also allows for aggregation of events and deletion of events.
 /// It is built using the builder pattern.
 /// The database is designed to work with Nostr events and uses the Nostr SDK for event handling.
 pub struct Database {
-    pub keys: Keys,
+    pub signer: Arc<dyn NostrSigner>,
+    pub public_key: PublicKey,
+    /// The keys hashing the `d` tags and encrypting the values.
+    pub(crate) keyring: Keyring,
+    /// The identities whose events are read: the identity itself, or the writers of a shared store.
+    pub(crate) authors: Vec<PublicKey>,
+    /// The access control list enforced on the reads of a shared store, if any.
+    pub(crate) access: Option<AccessControl>,
     pub relay_pool: RelayPool,
+    pub(crate) codec: CodecKind,
+    pub(crate) key_codecs: HashMap<String, CodecKind>,
+    pub(crate) compression: Option<CompressionKind>,
+    pub(crate) compression_threshold: usize,
+    pub(crate) chunk_size: usize,
+    pub(crate) key_index: Mutex<KeyIndex>,
+    pub(crate) timeout: Duration,
+    pub(crate) exit_policy: ReqExitPolicy,
+    pub(crate) write_quorum: WriteQuorum,
+    pub(crate) cache: Option<Arc<LocalCache>>,
+    pub(crate) outbox: Option<Arc<Outbox>>,
 }
 
-use sha2::Sha256;
-use hmac::{ Mac};
-
-// Create alias for HMAC-SHA256
-type HmacSha256 = Hmac<Sha256>;
-
-
-
-fn digest_hmac(secret_key : &SecretKey, key: &str) -> Result<String, NostrDBError>{
-    let mut mac = HmacSha256::new_from_slice(secret_key.as_secret_bytes()).map_err(|e| NostrDBError::GenerateTagError(e.to_string()))?;
-
-    mac.update(key.as_bytes());
-
-    let result = mac.finalize();
-
-    let code_bytes = result.into_bytes(); 
-
-    // code bytes to string
-    let code_bytes = hex::encode(code_bytes);
-
-    return Ok(code_bytes);
-    
-}
 impl Database {
     /// Constructs a Nostr filter for fetching events
-    async fn get_filter(&self, key: &str, kind: u16) -> Result<Filter, NostrDBError> {
-        Ok(
-            Filter::new()
+    pub(super) async fn get_filter(&self, key: &str, kind: u16) -> Result<Filter, NostrDBError> {
+        Ok(Filter::new()
             .kind(Kind::Custom(kind))
-            .author(self.keys.public_key)
+            .authors(self.authors_of(key))
             .custom_tag(
                 SingleLetterTag {
                     character: Alphabet::D,
                     uppercase: false,
                 },
-                digest_hmac(self.keys.secret_key(), key)?,
-            )
+                self.tag_hash(key),
+            ))
+    }
+
+    /// Returns the authors whose records of the given key are read.
+    pub(super) fn authors_of(&self, key: &str) -> Vec<PublicKey> {
+        self.authors
+            .iter()
+            .copied()
+            .filter(|author| {
+                self.access
+                    .as_ref()
+                    .is_none_or(|access| access.allows(author, key))
+            })
+            .collect()
+    }
+
+    /// Returns a `PermissionDenied` error unless the signer may write the given key.
+    /// The readers of a shared store may write no key, and its writers only the keys its access
+    /// control list lets them write, since the records of the others are not read.
+    pub(super) fn ensure_writable(&self, key: &str) -> Result<(), NostrDBError> {
+        let Some(access) = &self.access else {
+            return Ok(());
+        };
+        if self.authors.contains(&self.public_key) && access.allows(&self.public_key, key) {
+            return Ok(());
+        }
+        Err(NostrDBError::PermissionDenied {
+            key: key.to_string(),
+        })
+    }
+
+    /// Hashes the given key into the value of its `d` tag.
+    /// Keys of a bucket are hashed with a secret derived from the bucket name,
+    /// so that they never collide with the keys of the database or of other buckets.
+    pub(super) fn tag_hash(&self, key: &str) -> String {
+        hex::encode(hmac(self.keyring.tag(), key))
+    }
+
+    /// Constructs the `d` tag identifying the given key.
+    /// The key is hashed so that it is never published in clear.
+    pub(super) fn d_tag(&self, key: &str) -> Tag {
+        Tag::custom(
+            TagKind::SingleLetter(SingleLetterTag {
+                character: Alphabet::D,
+                uppercase: false,
+            }),
+            vec![self.tag_hash(key)],
         )
     }
 
-    async fn nip44_encrypt(&self, content: &str) -> Result<String, NostrDBError> {
-        let encrypted = self.keys
-            .nip44_encrypt(&self.keys.public_key, content)
-            .await
-            .map_err(|e: SignerError| NostrDBError::EncryptionError(e))?;
-        // info!("Encrypted '{}': '{}'", content, encrypted);
-        Ok(encrypted)
+    /// Compresses the content if a compression is configured and the content reaches its threshold.
+    /// It returns the content to encrypt and the tag flagging the compression, if it was applied.
+    fn compress(&self, content: &str) -> Result<(String, Option<Tag>), NostrDBError> {
+        let compression = match self.compression {
+            Some(compression) if content.len() >= self.compression_threshold => compression,
+            _ => return Ok((content.to_string(), None)),
+        };
+
+        let compressed = compression
+            .compress(content.as_bytes())
+            .map_err(|e| NostrDBError::CompressionError(e.to_string()))?;
+        let encoded = BASE64.encode(compressed);
+
+        // Small or incompressible values are stored as they are
+        if encoded.len() >= content.len() {
+            return Ok((content.to_string(), None));
+        }
+
+        let tag = Tag::custom(TagKind::custom(COMPRESSION_TAG), vec![compression.id()]);
+        Ok((encoded, Some(tag)))
     }
 
-    async fn nip44_decrypt(
+    /// Decrypts the content of a record, and decompresses it if it was stored compressed.
+    /// Records without a compression tag are returned as they were stored.
+    pub(super) async fn open_record(
         &self,
         pubkey: &PublicKey,
-        content: &str,
-    ) -> Result<String, NostrDBError> {
-        let decrypted = self.keys
-            .nip44_decrypt(pubkey, content)
-            .await
-            .map_err(|e| NostrDBError::DecryptionError(e))?;
-        // info!("Decrypted '{}': '{}'", content, decrypted);
-        Ok(decrypted)
+        mut record: NostrRecord,
+    ) -> Result<NostrRecord, NostrDBError> {
+        if record.tombstone {
+            return Ok(record);
+        }
+
+        record.content = self.nip44_decrypt(pubkey, &record.content).await?;
+
+        if !record.chunks.is_empty() {
+            let manifest: ChunkManifest = serde_json::from_str(&record.content)?;
+            record.content = self.read_chunks(pubkey, &record.chunks, &manifest).await?;
+        }
+
+        if let Some(id) = record.compression.take() {
+            let compression = CompressionKind::from_id(&id).ok_or_else(|| {
+                NostrDBError::CompressionError(format!("unsupported compression '{}'", id))
+            })?;
+            let compressed = BASE64
+                .decode(&record.content)
+                .map_err(|e| NostrDBError::CompressionError(e.to_string()))?;
+            let bytes = compression
+                .decompress(&compressed)
+                .map_err(|e| NostrDBError::CompressionError(e.to_string()))?;
+            record.content = String::from_utf8(bytes)
+                .map_err(|e| NostrDBError::CompressionError(e.to_string()))?;
+        }
+
+        Ok(record)
     }
 
+    /// Fetches the events matching the filter, through the local cache when the database has one.
+    /// The writes still queued in the outbox are merged with the events of the relays.
+    /// When no relay answers a read of consistency `One`, a database with a cache or an outbox
+    /// reads the events it holds: the cached ones and the queued ones.
+    pub(super) async fn fetch_events(
+        &self,
+        filter: Filter,
+        options: &QueryOptions,
+    ) -> Result<Events, NostrDBError> {
+        match self.fetch_answered(filter.clone(), options).await {
+            Err(NostrDBError::Timeout { relays })
+                if options.consistency == ReadConsistency::One
+                    && (self.cache.is_some() || self.outbox.is_some()) =>
+            {
+                tracing::warn!(?relays, "No relay answered, reading the local events.");
+                let events = match &self.cache {
+                    Some(cache) => cache.query(filter.clone()).await?,
+                    None => Events::new(&filter),
+                };
+                match &self.outbox {
+                    Some(outbox) => Ok(events.merge(outbox.matching(&filter).await)),
+                    None => Ok(events),
+                }
+            }
+            fetched => fetched,
+        }
+    }
+
+    /// Fetches the events matching the filter like `fetch_events`, but returns a `Timeout` error
+    /// when fewer relays than required by the read consistency answered, even with an outbox.
+    pub(super) async fn fetch_answered(
+        &self,
+        filter: Filter,
+        options: &QueryOptions,
+    ) -> Result<Events, NostrDBError> {
+        let events = match &self.cache {
+            // Reads beyond `One` compare the relays, so they always go to the network
+            Some(cache) if options.consistency == ReadConsistency::One => {
+                self.fetch_cached(cache, filter.clone(), options).await?
+            }
+            _ => self.fetch_remote(filter.clone(), options).await?,
+        };
+
+        match &self.outbox {
+            Some(outbox) => Ok(events.merge(outbox.matching(&filter).await)),
+            None => Ok(events),
+        }
+    }
+
+    /// Fetches the events matching the filter from each read relay of the pool, and merges them.
+    /// Fewer relays than required by the read consistency of the options answering in time
+    /// is a `Timeout` error listing the others. Beyond `One`, enough of the relays that answered
+    /// must also hold every event returned, or it returns an `InconsistentRead` error listing
+    /// the relays that are behind.
+    pub(super) async fn fetch_remote(
+        &self,
+        filter: Filter,
+        options: &QueryOptions,
+    ) -> Result<Events, NostrDBError> {
+        let fetch = self.fetch_by_relay(filter.clone(), options).await?;
+        let required = options.consistency.required(fetch.relays());
+
+        if fetch.answered.len() < required {
+            return Err(NostrDBError::Timeout {
+                relays: relay_names(fetch.late.iter()),
+            });
+        }
+        if !fetch.late.is_empty() {
+            tracing::warn!(relays = ?fetch.late, "Some relays did not answer in time.");
+        }
+
+        let mut events = Events::new(&filter);
+        for fetched in fetch.answered.values() {
+            events = events.merge(fetched.clone());
+        }
+
+        if options.consistency != ReadConsistency::One {
+            let behind = fetch.behind(&events);
+            if fetch.answered.len() - behind.len() < required {
+                return Err(NostrDBError::InconsistentRead {
+                    behind: relay_names(behind.keys()),
+                });
+            }
+        }
+        Ok(events)
+    }
+
+    /// Fetches the events matching the filter from each read relay of the pool, keeping them apart.
+    /// Relays are given the timeout and exit policy of the options, or of the database when unset.
+    pub(super) async fn fetch_by_relay(
+        &self,
+        filter: Filter,
+        options: &QueryOptions,
+    ) -> Result<RelayFetch, NostrDBError> {
+        let timeout = options.timeout.unwrap_or(self.timeout);
+        let policy = options.exit_policy.unwrap_or(self.exit_policy);
+
+        let relays = self
+            .relay_pool
+            .relays_with_flag(RelayServiceFlags::READ, FlagCheck::All)
+            .await;
+        if relays.is_empty() {
+            return Err(NostrDBError::NoRelaysProvided);
+        }
+
+        let fetches = relays.iter().map(|(url, relay)| {
+            let filter = filter.clone();
+            async move {
+                let start = Instant::now();
+                let result = relay.fetch_events(filter, timeout, policy).await;
+                (url, result, start.elapsed())
+            }
+        });
+
+        let mut fetch = RelayFetch::default();
+        for (url, result, elapsed) in future::join_all(fetches).await {
+            match result {
+                // A relay that didn't complete the request is stopped at the timeout
+                Ok(fetched) if elapsed < timeout => {
+                    fetch.answered.insert(url.clone(), fetched);
+                }
+                Ok(_) => fetch.late.push(url.clone()),
+                Err(e) => {
+                    tracing::warn!(url = %url, error = %e, "Failed to fetch events.");
+                    fetch.late.push(url.clone());
+                }
+            }
+        }
+        Ok(fetch)
+    }
 
     /// Constructs a new Nostr event and sends it to the relay pool.
-    async fn send_event(&self, builder: EventBuilder) -> Result<EventId, NostrDBError> {
+    /// It returns a `QuorumNotMet` error, holding the receipt of the event,
+    /// if fewer relays than the write quorum accepted it.
+    pub(super) async fn send_event(
+        &self,
+        builder: EventBuilder,
+    ) -> Result<StoreReceipt, NostrDBError> {
         let event = builder
-            .sign(&self.keys)
+            .sign(&self.signer)
             .await
             .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+        self.publish(&event).await
+    }
 
-        let output = self
-            .relay_pool
-            .send_event(&event)
-            .await
-            .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+    /// Sends a signed event to the relay pool, like `send_event`.
+    pub(super) async fn publish(&self, event: &Event) -> Result<StoreReceipt, NostrDBError> {
+        let receipt = match &self.outbox {
+            Some(outbox) => outbox.send(&self.relay_pool, event).await?,
+            None => {
+                let output = self
+                    .relay_pool
+                    .send_event(event)
+                    .await
+                    .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+                StoreReceipt {
+                    event_id: output.val,
+                    accepted: output.success,
+                    rejected: output.failed,
+                    queued: false,
+                }
+            }
+        };
+
+        // Queued events are published later, when the quorum is out of reach
+        if !receipt.queued && !receipt.meets(self.write_quorum) {
+            return Err(NostrDBError::QuorumNotMet {
+                required: self
+                    .write_quorum
+                    .required(receipt.accepted.len() + receipt.rejected.len()),
+                receipt: Box::new(receipt),
+            });
+        }
 
-        Ok(*output.id())
+        if let Some(cache) = &self.cache {
+            cache.save([event]).await?;
+        }
+        Ok(receipt)
     }
 
     /// Aggregates all non-aggregated events associated with the given key into a single event.
-    async fn aggregate<T: Into<String>>(&self, key: T) -> Result<(), NostrDBError> {
+    /// Legacy aggregates, which carry the key in clear, are merged into it and then deleted.
+    /// Only the events signed by this identity are aggregated: the records of the other writers
+    /// of a shared store stay in their own events, so that they keep their author.
+    /// It returns whether there were events to aggregate.
+    async fn aggregate<T: Into<String>>(&self, key: T) -> Result<bool, NostrDBError> {
         let key_str = key.into();
-        let non_aggregated = self.read_non_aggregates(&key_str, false).await?;
+        let options = QueryOptions::stored();
+        let non_aggregated = self.read_own_non_aggregates(&key_str, &options).await?;
+        let aggregates = self.fetch_own_aggregates(&key_str, &options).await?;
+        let legacy = self.legacy_aggregates(&key_str, &aggregates);
 
-        if non_aggregated.is_empty() {
-            return Err(NostrDBError::DatabaseError("No events to aggregate".into()));
+        if non_aggregated.is_empty() && legacy.is_empty() {
+            return Ok(false);
         }
 
-        let mut combined = self.read_aggregates(&key_str, false).await?;
+        let mut combined: BTreeSet<NostrRecord> = self
+            .open_aggregates(&aggregates, &options)
+            .await?
+            .into_keys()
+            .collect();
         combined.extend(non_aggregated.iter().cloned());
+        let combined = apply_tombstones(combined);
 
-        let content = serde_json::to_string(&combined)?;
-        let builder =
-            EventBuilder::new(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND), content).tag(Tag::custom(
-                TagKind::SingleLetter(SingleLetterTag {
-                    character: Alphabet::D,
-                    uppercase: false,
-                }),
-                vec![&key_str],
-            ));
+        self.publish_aggregate(&key_str, &combined).await?;
+        self.delete_events(&non_aggregated).await?;
+        self.delete_legacy_aggregates(legacy).await?;
+        Ok(true)
+    }
+
+    /// Publishes the aggregate event of the given key, holding the given records as stored.
+    pub(super) async fn publish_aggregate(
+        &self,
+        key: &str,
+        records: &BTreeSet<NostrRecord>,
+    ) -> Result<(), NostrDBError> {
+        // Records skip their empty fields, which only self-describing codecs can read back
+        let codec = match self.codec.is_self_describing() {
+            true => self.codec,
+            false => CodecKind::Json,
+        };
+        let content = codec
+            .encode(records)
+            .map_err(|e| NostrDBError::CodecError(e.to_string()))?;
+        let builder = EventBuilder::new(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND), content)
+            .tag(self.d_tag(key))
+            .tag(Tag::custom(TagKind::custom(CODEC_TAG), vec![codec.id()]));
 
         self.send_event(builder).await?;
-        self.delete_events(&non_aggregated).await?;
+        Ok(())
+    }
+
+    /// Moves the legacy aggregate of the given key, if any, under the hashed `d` tag.
+    /// Aggregates published before the `d` tags of aggregates were hashed carry the key in clear;
+    /// they are still read, and are migrated when their key is next read by its writer.
+    /// It takes the aggregates already fetched for the key, and returns whether one was migrated.
+    pub(crate) async fn migrate_legacy_aggregate(
+        &self,
+        key: &str,
+        aggregates: &[Event],
+    ) -> Result<bool, NostrDBError> {
+        let own: Vec<Event> = aggregates
+            .iter()
+            .filter(|event| event.pubkey == self.public_key)
+            .cloned()
+            .collect();
+        if self.legacy_aggregates(key, &own).is_empty() {
+            return Ok(false);
+        }
+        self.aggregate(key).await
+    }
+
+    /// Returns the ids of the legacy aggregates among the given aggregate events of a key.
+    fn legacy_aggregates(&self, key: &str, aggregates: &[Event]) -> Vec<EventId> {
+        aggregates
+            .iter()
+            .filter(|event| event.tags.identifier() == Some(key))
+            .map(|event| event.id)
+            .collect()
+    }
+
+    /// Deletes the given legacy aggregate events, once their records were aggregated again.
+    async fn delete_legacy_aggregates(&self, ids: Vec<EventId>) -> Result<(), NostrDBError> {
+        if !ids.is_empty() {
+            let delete_builder = EventBuilder::delete(
+                EventDeletionRequest::new()
+                    .ids(ids)
+                    .reason("migrated aggregate"),
+            );
+            self.send_event(delete_builder).await?;
+        }
         Ok(())
     }
 
@@ -147,67 +475,175 @@ impl Database {
 
     /// Reads non-aggregated events associated with the given key from the database.
     /// This method fetches all events associated with the key and returns them as a BTreeSet.
-    async fn read_non_aggregates<T: Into<String>>(
+    /// Only the events created within the range of the options are fetched.
+    pub(super) async fn read_non_aggregates<T: Into<String>>(
         &self,
         key: T,
-        decrypt: bool,
+        options: &QueryOptions,
     ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
         let key_str = key.into();
-        let events = self
-            .relay_pool
-            .fetch_events(
-                self.get_filter(&key_str, NOSTR_STORE_KIND).await?,
-                Duration::MAX,
-                ReqExitPolicy::default(),
-            )
-            .await
-            .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
-        
+        let filter = self.get_filter(&key_str, NOSTR_STORE_KIND).await?;
+        self.read_records(filter, options).await
+    }
+
+    /// Reads the non-aggregated records of the given key signed by this identity, the only ones
+    /// it can delete, and fold into its aggregate without taking over their authorship.
+    async fn read_own_non_aggregates(
+        &self,
+        key: &str,
+        options: &QueryOptions,
+    ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
+        let filter = Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_KIND))
+            .author(self.public_key)
+            .custom_tag(
+                SingleLetterTag {
+                    character: Alphabet::D,
+                    uppercase: false,
+                },
+                self.tag_hash(key),
+            );
+        self.read_records(filter, options).await
+    }
+
+    /// Reads the visible records of the events matching the filter, within the range of the options.
+    async fn read_records(
+        &self,
+        filter: Filter,
+        options: &QueryOptions,
+    ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
+        let filter = options.range().apply(filter);
+        let events = self.fetch_events(filter, options).await?;
+
         let mut records = BTreeSet::new();
-        for event in events {
-            let content = if decrypt {
-                self.nip44_decrypt(&event.pubkey, &event.content).await?
+        for (pubkey, record) in self.visible_records(&events, options).await? {
+            records.insert(if options.decrypt {
+                self.open_record(&pubkey, record).await?
             } else {
-                event.content.clone()
-            };
-            records.insert(NostrRecord::new(
-                event.created_at.as_u64(),
-                content,
-                event.id.to_string(),
-            ));
+                record
+            });
         }
 
         Ok(records)
     }
 
+    /// Returns the records of the given events that readers can see, along with their authors.
+    pub(super) async fn visible_records(
+        &self,
+        events: &Events,
+        options: &QueryOptions,
+    ) -> Result<Vec<(PublicKey, NostrRecord)>, NostrDBError> {
+        // Records of write batches are only visible once their batch is committed
+        let committed = self.committed_batches(events, options).await?;
+
+        // Relays may still return expired events
+        let now = Timestamp::now().as_u64();
+        Ok(events
+            .iter()
+            .map(|event| (event.pubkey, NostrRecord::from(event)))
+            .filter(|(_, record)| !record.is_expired(now))
+            .filter(|(_, record)| {
+                record
+                    .batch
+                    .as_ref()
+                    .is_none_or(|batch| committed.contains(batch))
+            })
+            .collect())
+    }
+
     /// Reads aggregated events associated with the given key from the database.
-    /// This method fetches the first event associated with the key and returns it as a BTreeSet.
-    async fn read_aggregates(
+    /// This method fetches the latest aggregate event of each author associated with the key
+    /// and returns their records as a BTreeSet.
+    /// The aggregate event is newer than the records it holds, so the range is applied to the records.
+    pub(super) async fn read_aggregates(
         &self,
         key: &str,
-        decrypt: bool,
+        options: &QueryOptions,
     ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
+        Ok(self
+            .read_authored_aggregates(key, options)
+            .await?
+            .into_keys()
+            .collect())
+    }
+
+    /// Reads aggregated records like `read_aggregates`, along with the author of the aggregate
+    /// event each of them was read from.
+    pub(super) async fn read_authored_aggregates(
+        &self,
+        key: &str,
+        options: &QueryOptions,
+    ) -> Result<BTreeMap<NostrRecord, PublicKey>, NostrDBError> {
+        let aggregates = self.fetch_aggregates(key, options).await?;
+        self.open_aggregates(&aggregates, options).await
+    }
+
+    /// Fetches the latest aggregate event of each author associated with the given key.
+    async fn fetch_aggregates(
+        &self,
+        key: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<Event>, NostrDBError> {
+        let filter = self.get_filter(key, NOSTR_STORE_AGGREGATE_KIND).await?;
         let events = self
-            .relay_pool
-            .fetch_events(
-                self.get_filter(key, NOSTR_STORE_AGGREGATE_KIND).await?,
-                Duration::MAX,
-                ReqExitPolicy::default(),
-            )
-            .await
-            .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+            .fetch_events(self.with_legacy_identifier(key, filter), options)
+            .await?;
 
-        if let Some(event) = events.first() {
-            let mut records: Vec<NostrRecord> = serde_json::from_str(&event.content)?;
-            if decrypt {
-                for record in &mut records {
-                    record.content = self.nip44_decrypt(&event.pubkey, &record.content).await?;
+        let mut latest = HashSet::new();
+        Ok(events
+            .into_iter()
+            .filter(|event| {
+                latest.insert((event.pubkey, event.tags.identifier().map(str::to_string)))
+            })
+            .collect())
+    }
+
+    /// Fetches the latest aggregate event of the given key signed by this identity, if any.
+    async fn fetch_own_aggregates(
+        &self,
+        key: &str,
+        options: &QueryOptions,
+    ) -> Result<Vec<Event>, NostrDBError> {
+        let mut aggregates = self.fetch_aggregates(key, options).await?;
+        aggregates.retain(|event| event.pubkey == self.public_key);
+        Ok(aggregates)
+    }
+
+    /// Adds the key in clear to the identifiers of the filter for databases of the identity scheme,
+    /// whose legacy aggregates were published before the `d` tags of aggregates were hashed.
+    pub(super) fn with_legacy_identifier(&self, key: &str, filter: Filter) -> Filter {
+        match self.keyring.has_legacy_aggregates() {
+            true => filter.identifier(key),
+            false => filter,
+        }
+    }
+
+    /// Returns the records of the given aggregate events within the range of the options,
+    /// along with the authors of their aggregate events.
+    async fn open_aggregates(
+        &self,
+        aggregates: &[Event],
+        options: &QueryOptions,
+    ) -> Result<BTreeMap<NostrRecord, PublicKey>, NostrDBError> {
+        let range = options.range();
+
+        // Expired records are dropped, so that aggregating doesn't copy them forever
+        let now = Timestamp::now().as_u64();
+        let mut opened = BTreeMap::new();
+        for event in aggregates {
+            for record in aggregate_records(event)? {
+                if record.is_expired(now) || !range.contains(record.created_at) {
+                    continue;
                 }
+                let record = if options.decrypt {
+                    self.open_record(&event.pubkey, record).await?
+                } else {
+                    record
+                };
+                opened.insert(record, event.pubkey);
             }
-            Ok(records.into_iter().collect())
-        } else {
-            Ok(BTreeSet::new())
         }
+        Ok(opened)
     }
 
     /// Creates a new instance of the Database struct.
@@ -215,46 +651,257 @@ impl Database {
         DatabaseBuilder::new(keys)
     }
 
+    /// Returns a handle to the bucket with the given name.
+    /// The bucket shares the relay pool and the configuration of the database,
+    /// but its keys are isolated from the database keys and from the other buckets.
+    pub fn bucket<T: Into<String>>(&self, name: T) -> Bucket {
+        let name = name.into();
+        let keyring = self.keyring.bucket(&name);
+        let mut database = self.with_identity(self.signer.clone(), self.public_key, keyring);
+        database.authors = self.authors.clone();
+        database.access = self.access.clone();
+        Bucket::new(name, database)
+    }
+
+    /// Returns a database with the same relays and configuration, acting as the given identity.
+    pub(super) fn with_identity(
+        &self,
+        signer: Arc<dyn NostrSigner>,
+        public_key: PublicKey,
+        keyring: Keyring,
+    ) -> Database {
+        Database {
+            signer,
+            public_key,
+            keyring,
+            authors: vec![public_key],
+            access: None,
+            relay_pool: self.relay_pool.clone(),
+            codec: self.codec,
+            key_codecs: self.key_codecs.clone(),
+            compression: self.compression,
+            compression_threshold: self.compression_threshold,
+            chunk_size: self.chunk_size,
+            key_index: Mutex::new(KeyIndex::default()),
+            timeout: self.timeout,
+            exit_policy: self.exit_policy,
+            write_quorum: self.write_quorum,
+            cache: self.cache.clone(),
+            outbox: self.outbox.clone(),
+        }
+    }
+
     /// Stores a new key-value pair in the database.
     /// The content is encrypted using the NIP-44 encryption scheme.
+    /// The receipt lists the relays that accepted and rejected the event.
     pub async fn store<T: Into<String>>(
         &self,
         key: T,
         content: &str,
-    ) -> Result<EventId, NostrDBError> {
-        let encrypted = self.nip44_encrypt(content).await?;
+    ) -> Result<StoreReceipt, NostrDBError> {
+        self.store_with_tags(&key.into(), content, vec![]).await
+    }
 
-        let builder =
-            EventBuilder::new(Kind::Custom(NOSTR_STORE_KIND), encrypted).tag(Tag::custom(
-                TagKind::SingleLetter(SingleLetterTag {
-                    character: Alphabet::D,
-                    uppercase: false,
-                }),
-                vec![ digest_hmac(&self.keys.secret_key(), &key.into())? ],
-            ));
+    /// Stores a new key-value pair in the database that expires after the given time-to-live.
+    /// The event carries a NIP-40 expiration tag, so relays may drop it once it expired,
+    /// and expired values are ignored by reads even if a relay still returns them.
+    pub async fn store_with_ttl<T: Into<String>>(
+        &self,
+        key: T,
+        content: &str,
+        ttl: Duration,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        let expiration = Tag::expiration(Timestamp::now() + ttl);
+        self.store_with_tags(&key.into(), content, vec![expiration])
+            .await
+    }
+
+    /// Stores a new key-value pair in the database, attaching the given extra tags to the event.
+    pub(super) async fn store_with_tags(
+        &self,
+        key: &str,
+        content: &str,
+        tags: Vec<Tag>,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        self.ensure_writable(key)?;
+        let builder = self.build_record(key, content, tags).await?;
+        let receipt = self.send_event(builder).await?;
+        self.index_key(key).await;
+        Ok(receipt)
+    }
+
+    /// Constructs the event storing a new value of the given key, attaching the given extra tags.
+    /// The content is compressed, chunked and encrypted as configured; chunks are published right away.
+    pub(super) async fn build_record(
+        &self,
+        key: &str,
+        content: &str,
+        tags: Vec<Tag>,
+    ) -> Result<EventBuilder, NostrDBError> {
+        // Chunks expire along with their manifest
+        let expiration = tags
+            .iter()
+            .find(|tag| tag.kind() == TagKind::Expiration)
+            .cloned();
+        let (encrypted, compression_tag, chunks) = self.seal(content, expiration).await?;
+        let chunk_tags = chunks
+            .iter()
+            .map(|id| Tag::custom(TagKind::custom(CHUNK_TAG), vec![id.to_hex()]));
+
+        Ok(EventBuilder::new(Kind::Custom(NOSTR_STORE_KIND), encrypted)
+            .tag(self.d_tag(key))
+            .tags(tags)
+            .tags(compression_tag)
+            .tags(chunk_tags))
+    }
+
+    /// Seals an opened record as this database stores it, the inverse of `open_record`.
+    /// Its content is compressed, chunked and encrypted as configured; chunks are published right away.
+    pub(super) async fn seal_record(
+        &self,
+        mut record: NostrRecord,
+    ) -> Result<NostrRecord, NostrDBError> {
+        if record.tombstone {
+            return Ok(record);
+        }
+
+        let expiration = record
+            .expiration
+            .map(|expiration| Tag::expiration(Timestamp::from(expiration)));
+        let (encrypted, compression_tag, chunks) = self.seal(&record.content, expiration).await?;
+        record.content = encrypted;
+        record.compression = compression_tag
+            .as_ref()
+            .and_then(|tag| tag.content())
+            .map(str::to_string);
+        record.chunks = chunks.iter().map(EventId::to_hex).collect();
+        Ok(record)
+    }
+
+    /// Compresses, chunks and encrypts a value as configured, publishing the chunks right away.
+    /// It returns the encrypted content, the compression tag and the ids of the chunk events.
+    async fn seal(
+        &self,
+        content: &str,
+        expiration: Option<Tag>,
+    ) -> Result<(String, Option<Tag>, Vec<EventId>), NostrDBError> {
+        let (mut content, compression_tag) = self.compress(content)?;
+
+        // Values too large for a single event are published as chunks referenced by a manifest
+        let mut chunks = Vec::new();
+        if content.len() > self.chunk_size {
+            chunks = self.send_chunks(&content, expiration).await?;
+            content = serde_json::to_string(&ChunkManifest::new(&content))?;
+        }
+
+        let encrypted = self.nip44_encrypt(&content).await?;
+        Ok((encrypted, compression_tag, chunks))
+    }
+
+    /// Splits the content into chunks and publishes each of them as an encrypted chunk event.
+    /// It returns the ids of the chunk events, in order.
+    async fn send_chunks(
+        &self,
+        content: &str,
+        expiration: Option<Tag>,
+    ) -> Result<Vec<EventId>, NostrDBError> {
+        let mut ids = Vec::new();
+        for chunk in split_chunks(content, self.chunk_size) {
+            let encrypted = self.nip44_encrypt(chunk).await?;
+            let builder = EventBuilder::new(Kind::Custom(NOSTR_STORE_CHUNK_KIND), encrypted)
+                .tags(expiration.clone());
+            ids.push(self.send_event(builder).await?.event_id);
+        }
+        Ok(ids)
+    }
+
+    /// Fetches the chunk events of a chunked value, and reassembles the value.
+    /// It returns an error if a chunk is missing on every relay or if the value doesn't match its hash.
+    async fn read_chunks(
+        &self,
+        pubkey: &PublicKey,
+        chunk_ids: &[String],
+        manifest: &ChunkManifest,
+    ) -> Result<String, NostrDBError> {
+        let ids = chunk_ids
+            .iter()
+            .map(|id| EventId::parse(id).map_err(|e| NostrDBError::NostrError(e.to_string())))
+            .collect::<Result<Vec<EventId>, NostrDBError>>()?;
+
+        let filter = Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_CHUNK_KIND))
+            .author(*pubkey)
+            .ids(ids.clone());
+        let events = self.fetch_events(filter, &QueryOptions::default()).await?;
+
+        // The manifest is read from the relays, so the value grows with the chunks decrypted
+        // instead of being allocated with the length it claims
+        let mut content = String::new();
+        for id in ids {
+            let event = events
+                .iter()
+                .find(|event| event.id == id)
+                .ok_or_else(|| NostrDBError::MissingChunk(id.to_hex()))?;
+            content.push_str(&self.nip44_decrypt(&event.pubkey, &event.content).await?);
+            if content.len() > manifest.length {
+                return Err(NostrDBError::ChunkHashMismatch(manifest.hash.clone()));
+            }
+        }
+
+        if !manifest.verify(&content) {
+            return Err(NostrDBError::ChunkHashMismatch(manifest.hash.clone()));
+        }
+        Ok(content)
+    }
+
+    /// Deletes the chunk events referenced by the specified records.
+    async fn delete_chunks<'a>(
+        &self,
+        records: impl Iterator<Item = &'a NostrRecord>,
+    ) -> Result<(), NostrDBError> {
+        let ids: Vec<EventId> = records
+            .flat_map(|rec| rec.chunks.iter())
+            .filter_map(|id| EventId::parse(id).ok())
+            .collect();
+
+        if !ids.is_empty() {
+            let delete_builder =
+                EventBuilder::delete(EventDeletionRequest::new().ids(ids).reason("delete chunks"));
+            self.send_event(delete_builder).await?;
+        }
 
-        self.send_event(builder).await
+        Ok(())
     }
 
     /// Removes all values associated with the given key from the database.
     /// This includes deleting the events and resetting the aggregate event to empty.
     pub async fn remove<T: Into<String>>(&self, key: T) -> Result<(), NostrDBError> {
         let key_str = key.into();
-        let records = self.read_non_aggregates(&key_str, false).await?;
+        self.ensure_writable(&key_str)?;
+        // Only the events signed by this identity can be deleted
+        let options = QueryOptions::stored();
+        let records = self.read_own_non_aggregates(&key_str, &options).await?;
+        let aggregates = self.fetch_own_aggregates(&key_str, &options).await?;
+        let aggregated = self.open_aggregates(&aggregates, &options).await?;
         self.delete_events(&records).await?;
+        self.delete_chunks(records.iter().chain(aggregated.keys()))
+            .await?;
+        self.delete_legacy_aggregates(self.legacy_aggregates(&key_str, &aggregates))
+            .await?;
 
         // Reset the aggregate event to empty
         let empty = serde_json::to_string(&BTreeSet::<NostrRecord>::new())?;
-        let builder =
-            EventBuilder::new(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND), empty).tag(Tag::custom(
-                TagKind::SingleLetter(SingleLetterTag {
-                    character: Alphabet::D,
-                    uppercase: false,
-                }),
-                vec![&key_str],
-            ));
+        let builder = EventBuilder::new(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND), empty)
+            .tag(self.d_tag(&key_str));
 
         self.send_event(builder).await?;
+
+        // The records of the other writers of a shared store can't be deleted, so they are
+        // shadowed by a tombstone instead
+        if self.authors.iter().any(|author| *author != self.public_key) {
+            self.send_event(self.tombstone(&key_str)?).await?;
+        }
+        self.unindex_key(&key_str).await;
         Ok(())
     }
 
@@ -262,44 +909,193 @@ impl Database {
     /// This method fetches the history of events associated with the key and returns the last one.
     /// If no events are found, it returns an error.
     pub async fn read<T: Into<String>>(&self, key: T) -> Result<String, NostrDBError> {
-        let history = self.read_history(key, QueryOptions::default()).await?;
-        let last = history
-            .last()
-            .ok_or_else(|| NostrDBError::DatabaseError("Variable not found".into()))?;
+        Ok(self.read_last(key).await?.content)
+    }
 
-        Ok(last.content.clone())
+    /// Reads the value associated with the given key at the given point in time.
+    /// Only the records created up to that time are fetched, including the aggregated ones.
+    pub async fn read_at<T: Into<String>>(
+        &self,
+        key: T,
+        timestamp: Timestamp,
+    ) -> Result<String, NostrDBError> {
+        let options = QueryOptions::default().with_until(timestamp);
+        Ok(self.read_last_in(&key.into(), options).await?.content)
+    }
+
+    /// Reads the last record associated with the given key from the database.
+    async fn read_last<T: Into<String>>(&self, key: T) -> Result<NostrRecord, NostrDBError> {
+        self.read_last_in(&key.into(), QueryOptions::default())
+            .await
+    }
+
+    /// Reads the last record associated with the given key, among the ones selected by the options.
+    /// Records written by `compare_and_swap` always come after the version they replaced.
+    async fn read_last_in(
+        &self,
+        key: &str,
+        options: QueryOptions,
+    ) -> Result<NostrRecord, NostrDBError> {
+        let history = self.read_history_in(key, options).await?;
+        heads(&history)
+            .last()
+            .map(|record| (*record).clone())
+            .ok_or_else(|| NostrDBError::DatabaseError("Variable not found".into()))
     }
 
     /// Reads the history of values associated with the given key from the database.
     /// This method fetches all events associated with the key and returns them as a BTreeSet.
     /// The events are sorted by their creation time.
+    /// Only the records within the `since`/`until` range of the options are returned, and when a
+    /// `limit` is set, only the first records in the order of the options are kept: they are
+    /// read like `history_stream`, fetching only the pages holding them.
     pub async fn read_history<T: Into<String>>(
         &self,
         key: T,
         options: QueryOptions,
     ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
-        let key_str = key.into();
-        let mut records = self.read_non_aggregates(&key_str, options.decrypt).await?;
+        self.read_history_in(&key.into(), options).await
+    }
 
-        let should_aggregate = records.len() > options.aggregate_count;
+    /// Reads the history of values associated with the given key, as selected by the options.
+    /// Events are only aggregated when the whole history is read.
+    pub(super) async fn read_history_in(
+        &self,
+        key: &str,
+        options: QueryOptions,
+    ) -> Result<BTreeSet<NostrRecord>, NostrDBError> {
+        // Only the pages holding the records returned are fetched
+        if options.limit.is_some() {
+            return self.history_stream(key, options).try_collect().await;
+        }
+
+        let key_str = key.to_string();
+        let range = options.range();
+        let mut records = self.read_non_aggregates(&key_str, &options).await?;
 
-        records.append(&mut self.read_aggregates(&key_str, options.decrypt).await?);
+        // Aggregates are published by the reader, so only keys it may write are aggregated
+        let should_aggregate = range.is_unbounded()
+            && records.len() > options.aggregate_count
+            && self.ensure_writable(&key_str).is_ok();
+
+        let aggregates = self.fetch_aggregates(&key_str, &options).await?;
+        records.extend(
+            self.open_aggregates(&aggregates, &options)
+                .await?
+                .into_keys(),
+        );
 
         if should_aggregate {
             self.aggregate(&key_str).await?;
+        } else if self.ensure_writable(&key_str).is_ok() {
+            self.migrate_legacy_aggregate(&key_str, &aggregates).await?;
         }
 
+        let mut records = apply_tombstones(records);
+        records.retain(|record| !record.tombstone);
         Ok(records)
     }
 
+    /// Stores a serializable value associated with the given key in the database.
+    /// The value is encoded with the codec configured for the key before being encrypted,
+    /// and the codec identifier is stored in the event tags.
+    pub async fn put<K: Into<String>, T: Serialize>(
+        &self,
+        key: K,
+        value: &T,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        let key_str = key.into();
+        let codec = self.codec_for(&key_str);
+        let payload = codec
+            .encode(value)
+            .map_err(|e| NostrDBError::CodecError(e.to_string()))?;
+        let codec_tag = Tag::custom(TagKind::custom(CODEC_TAG), vec![codec.id()]);
+
+        self.store_with_tags(&key_str, &payload, vec![codec_tag])
+            .await
+    }
+
+    /// Reads the last value associated with the given key and decodes it.
+    /// It returns a `DecodeError` if the stored value cannot be decoded into `T`.
+    pub async fn get<K: Into<String>, T: DeserializeOwned>(
+        &self,
+        key: K,
+    ) -> Result<T, NostrDBError> {
+        let key_str = key.into();
+        let record = self.read_last(&key_str).await?;
+        self.decode(&key_str, &record)
+    }
+
+    /// Reads the history of values associated with the given key and decodes each of them.
+    /// The records are decrypted regardless of `options.decrypt`, and sorted by their creation time.
+    pub async fn get_history<K: Into<String>, T: DeserializeOwned>(
+        &self,
+        key: K,
+        options: QueryOptions,
+    ) -> Result<Vec<TypedRecord<T>>, NostrDBError> {
+        let key_str = key.into();
+        let options = QueryOptions {
+            decrypt: true,
+            ..options
+        };
+        let records = self.read_history(&key_str, options).await?;
+
+        records
+            .into_iter()
+            .map(|record| {
+                Ok(TypedRecord {
+                    created_at: record.created_at,
+                    value: self.decode(&key_str, &record)?,
+                    event_id: record.event_id,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the codec used to encode the values of the given key.
+    pub(super) fn codec_for(&self, key: &str) -> CodecKind {
+        self.key_codecs.get(key).copied().unwrap_or(self.codec)
+    }
+
+    /// Decodes a decrypted record of the given key with the codec it was stored with.
+    /// Records without a codec tag are decoded as JSON.
+    fn decode<T: DeserializeOwned>(
+        &self,
+        key: &str,
+        record: &NostrRecord,
+    ) -> Result<T, NostrDBError> {
+        let decode_error = |reason: String| NostrDBError::DecodeError {
+            key: key.to_string(),
+            reason,
+        };
+
+        let codec = match &record.codec {
+            Some(id) => CodecKind::from_id(id)
+                .ok_or_else(|| decode_error(format!("unsupported codec '{}'", id)))?,
+            None => CodecKind::Json,
+        };
+
+        codec
+            .decode(&record.content)
+            .map_err(|e| decode_error(e.to_string()))
+    }
+
     /// Stores an event-operation in the database.
+    /// The operation is encoded with the codec configured for the key, as `put` does.
     pub async fn store_event<I: Into<String>, O: Operation>(
         &self,
         key: I,
         operation: O,
-    ) -> Result<EventId, NostrDBError> {
-        let serialized = operation.serialize().map_err(|e| NostrDBError::EventStreamError(e.to_string()))?;
-        self.store(key, &serialized).await
+    ) -> Result<StoreReceipt, NostrDBError> {
+        let key_str = key.into();
+        let codec = self.codec_for(&key_str);
+        let payload = operation
+            .encode(codec)
+            .map_err(|e| NostrDBError::EventStreamError(e.to_string()))?;
+        let codec_tag = Tag::custom(TagKind::custom(CODEC_TAG), vec![codec.id()]);
+
+        self.store_with_tags(&key_str, &payload, vec![codec_tag])
+            .await
     }
 
     /// Reads the event-stream processed by the given operation.
@@ -310,14 +1106,43 @@ impl Database {
         O: Operation,
     {
         let records = self.read_history(key, QueryOptions::default()).await?;
-        let mut acc = O::default();
+        fold_operations::<O>(records)
+    }
 
-        for record in records {
-            let op = O::deserialize(record.content)
-                .map_err(|e| NostrDBError::EventStreamError(e.to_string()))?;
-            acc = op.apply(acc);
-        }
+    /// Reads the event-stream processed by the given operation, as it was at the given point in time.
+    /// Only the operations stored up to that time are applied, including the aggregated ones.
+    pub async fn read_event_at<O>(
+        &self,
+        key: impl Into<String>,
+        timestamp: Timestamp,
+    ) -> Result<O::Value, NostrDBError>
+    where
+        O: Operation,
+    {
+        let records = self
+            .read_history_in(&key.into(), QueryOptions::default().with_until(timestamp))
+            .await?;
+        fold_operations::<O>(records)
+    }
+}
+
+/// Applies the operations stored in the records, in order, starting from the default value.
+/// Records without a codec tag were stored as returned by `Operation::serialize`.
+pub(super) fn fold_operations<O: Operation>(
+    records: BTreeSet<NostrRecord>,
+) -> Result<O::Value, NostrDBError> {
+    let mut acc = O::default();
 
-        Ok(acc)
+    for record in records {
+        let op = match &record.codec {
+            Some(id) => CodecKind::from_id(id)
+                .ok_or_else(|| format!("unsupported codec '{}'", id).into())
+                .and_then(|codec| O::decode(codec, record.content)),
+            None => O::deserialize(record.content).map_err(|e| e.to_string().into()),
+        }
+        .map_err(|e| NostrDBError::EventStreamError(e.to_string()))?;
+        acc = op.apply(acc);
     }
+
+    Ok(acc)
 }
@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use nostr_sdk::prelude::*;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+use crate::NostrDBError;
+
+/// Name of the file of a cache directory holding the events, one JSON event per line.
+const EVENTS_FILE: &str = "events.jsonl";
+
+/// A local cache persisted in a directory, enabled by the `file-cache` feature.
+/// Events are kept in memory and appended to a log, which is replayed when the cache is opened,
+/// so that deletion requests and replaced versions are applied again, and then compacted.
+#[derive(Debug)]
+pub(crate) struct FileDatabase {
+    memory: MemoryDatabase,
+    path: PathBuf,
+    log: Mutex<File>,
+}
+
+impl FileDatabase {
+    /// Opens the cache in the given directory, creating it if it doesn't exist.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, NostrDBError> {
+        fs::create_dir_all(&path).await.map_err(file_error)?;
+        let path = path.as_ref().join(EVENTS_FILE);
+
+        let memory = MemoryDatabase::with_opts(MemoryDatabaseOptions {
+            events: true,
+            max_events: None,
+        });
+        match fs::read_to_string(&path).await {
+            Ok(log) => {
+                for line in log.lines().filter(|line| !line.is_empty()) {
+                    // A line cut short by a crash is skipped
+                    if let Ok(event) = Event::from_json(line) {
+                        memory.save_event(&event).await.map_err(file_error)?;
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(file_error(e)),
+        }
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await
+            .map_err(file_error)?;
+        let database = Self {
+            memory,
+            path,
+            log: Mutex::new(log),
+        };
+        database.compact().await.map_err(file_error)?;
+        Ok(database)
+    }
+
+    /// Rewrites the log with the events currently held, from the oldest to the newest.
+    async fn compact(&self) -> Result<(), DatabaseError> {
+        let events = self.memory.query(Filter::new()).await?;
+        let mut log = String::new();
+        for event in events.into_iter().rev() {
+            log.push_str(&event.as_json());
+            log.push('\n');
+        }
+
+        let mut file = self.log.lock().await;
+        fs::write(&self.path, log)
+            .await
+            .map_err(DatabaseError::backend)?;
+        *file = OpenOptions::new()
+            .append(true)
+            .open(&self.path)
+            .await
+            .map_err(DatabaseError::backend)?;
+        Ok(())
+    }
+}
+
+impl NostrDatabase for FileDatabase {
+    fn backend(&self) -> Backend {
+        Backend::Custom("file".to_string())
+    }
+}
+
+impl NostrEventsDatabase for FileDatabase {
+    fn save_event<'a>(
+        &'a self,
+        event: &'a Event,
+    ) -> BoxedFuture<'a, Result<SaveEventStatus, DatabaseError>> {
+        Box::pin(async move {
+            let status = self.memory.save_event(event).await?;
+            if status.is_success() {
+                let mut line = event.as_json();
+                line.push('\n');
+                let mut log = self.log.lock().await;
+                log.write_all(line.as_bytes())
+                    .await
+                    .map_err(DatabaseError::backend)?;
+                log.flush().await.map_err(DatabaseError::backend)?;
+            }
+            Ok(status)
+        })
+    }
+
+    fn check_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<DatabaseEventStatus, DatabaseError>> {
+        self.memory.check_id(event_id)
+    }
+
+    fn has_coordinate_been_deleted<'a>(
+        &'a self,
+        coordinate: &'a CoordinateBorrow<'a>,
+        timestamp: &'a Timestamp,
+    ) -> BoxedFuture<'a, Result<bool, DatabaseError>> {
+        self.memory
+            .has_coordinate_been_deleted(coordinate, timestamp)
+    }
+
+    fn event_by_id<'a>(
+        &'a self,
+        event_id: &'a EventId,
+    ) -> BoxedFuture<'a, Result<Option<Event>, DatabaseError>> {
+        self.memory.event_by_id(event_id)
+    }
+
+    fn count(&self, filter: Filter) -> BoxedFuture<'_, Result<usize, DatabaseError>> {
+        self.memory.count(filter)
+    }
+
+    fn query(&self, filter: Filter) -> BoxedFuture<'_, Result<Events, DatabaseError>> {
+        self.memory.query(filter)
+    }
+
+    fn delete(&self, filter: Filter) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async move {
+            self.memory.delete(filter).await?;
+            self.compact().await
+        })
+    }
+}
+
+impl NostrDatabaseWipe for FileDatabase {
+    fn wipe(&self) -> BoxedFuture<'_, Result<(), DatabaseError>> {
+        Box::pin(async move {
+            self.memory.wipe().await?;
+            self.compact().await
+        })
+    }
+}
+
+fn file_error<E: std::fmt::Display>(e: E) -> NostrDBError {
+    NostrDBError::DatabaseError(format!("Local cache error: {}", e))
+}
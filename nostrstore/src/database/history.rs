@@ -0,0 +1,369 @@
+use std::collections::{HashSet, VecDeque};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use nostr_sdk::prelude::*;
+
+use super::NostrRecord;
+use super::changes::BacklogPages;
+use super::core::{Database, NOSTR_STORE_KIND};
+use super::query::{HistoryOrder, QueryOptions, TimeRange};
+use crate::NostrDBError;
+
+/// Number of events requested from the relays for each page of a history stream.
+pub const HISTORY_PAGE_SIZE: usize = 500;
+
+/// Walks the history of a key from the newest record to the oldest, one page at a time.
+/// Records are returned as they were stored, without being decrypted.
+struct HistoryPages {
+    key: String,
+    /// The options of the stream, reading the records as they were stored.
+    options: QueryOptions,
+    range: TimeRange,
+    /// The creation time of the oldest record fetched so far, from which the next page starts.
+    cursor: Option<Timestamp>,
+    /// The ids of the records created at the cursor time, that the next page returns again.
+    seen: HashSet<EventId>,
+    buffer: VecDeque<(PublicKey, NostrRecord)>,
+    /// The number of events requested for each page, no more than the records to return.
+    page_size: usize,
+    /// The tombstone the walk stopped at, if any.
+    tombstone: Option<NostrRecord>,
+    pending_done: bool,
+    aggregates_done: bool,
+}
+
+impl HistoryPages {
+    fn new(key: String, options: &QueryOptions) -> Self {
+        let range = options.range();
+        Self {
+            key,
+            options: QueryOptions {
+                decrypt: false,
+                ..options.clone()
+            },
+            cursor: range.until,
+            range,
+            seen: HashSet::new(),
+            buffer: VecDeque::new(),
+            page_size: options
+                .limit
+                .map_or(HISTORY_PAGE_SIZE, |limit| limit.clamp(1, HISTORY_PAGE_SIZE)),
+            tombstone: None,
+            pending_done: false,
+            aggregates_done: false,
+        }
+    }
+
+    /// Returns the next record of the history, fetching a new page when the buffer is empty.
+    /// The walk stops at the first tombstone, since it removes all the older records.
+    async fn next(
+        &mut self,
+        database: &Database,
+    ) -> Result<Option<(PublicKey, NostrRecord)>, NostrDBError> {
+        loop {
+            if let Some((pubkey, record)) = self.buffer.pop_front() {
+                if record.tombstone {
+                    self.buffer.clear();
+                    self.pending_done = true;
+                    self.aggregates_done = true;
+                    self.tombstone = Some(record);
+                    return Ok(None);
+                }
+                return Ok(Some((pubkey, record)));
+            }
+
+            if !self.pending_done {
+                self.fetch_page(database).await?;
+            } else if !self.aggregates_done {
+                // Aggregated records are older than the ones still waiting to be aggregated
+                self.aggregates_done = true;
+                let aggregated = database
+                    .read_authored_aggregates(&self.key, &self.options)
+                    .await?;
+                self.buffer.extend(
+                    aggregated
+                        .into_iter()
+                        .rev()
+                        .map(|(record, pubkey)| (pubkey, record)),
+                );
+            } else {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Fetches the page of records created at or before the cursor.
+    /// Records sharing the creation time of the cursor are returned again by the relays, so
+    /// the ones already seen are skipped.
+    async fn fetch_page(&mut self, database: &Database) -> Result<(), NostrDBError> {
+        let since = self.range.since;
+        let events = self
+            .fetch(database, since, self.cursor, Some(self.page_size))
+            .await?;
+        let full = events.len() >= self.page_size;
+
+        let fresh = self.unseen(&events);
+        let Some(oldest) = fresh.iter().map(|(created_at, _)| *created_at).min() else {
+            match self.cursor {
+                // A full page of records created in the same second: read the whole second,
+                // then move past it
+                Some(cursor) if full => {
+                    let events = self
+                        .fetch(database, Some(cursor), Some(cursor), None)
+                        .await?;
+                    let fresh = self.unseen(&events);
+                    self.buffer_records(database, &events, fresh).await?;
+                    self.seen.clear();
+                    match cursor.as_u64().checked_sub(1) {
+                        Some(previous) => self.cursor = Some(Timestamp::from(previous)),
+                        None => self.pending_done = true,
+                    }
+                }
+                _ => self.pending_done = true,
+            }
+            return Ok(());
+        };
+
+        if !full {
+            self.pending_done = true;
+        }
+        if self.cursor != Some(oldest) {
+            self.seen.clear();
+        }
+        self.cursor = Some(oldest);
+        self.seen.extend(
+            events
+                .iter()
+                .filter(|event| event.created_at == oldest)
+                .map(|event| event.id),
+        );
+
+        self.buffer_records(database, &events, fresh).await
+    }
+
+    /// Fetches the records of the key created within the given bounds.
+    async fn fetch(
+        &self,
+        database: &Database,
+        since: Option<Timestamp>,
+        until: Option<Timestamp>,
+        limit: Option<usize>,
+    ) -> Result<Events, NostrDBError> {
+        let mut filter = database.get_filter(&self.key, NOSTR_STORE_KIND).await?;
+        if let Some(since) = since {
+            filter = filter.since(since);
+        }
+        if let Some(until) = until {
+            filter = filter.until(until);
+        }
+        if let Some(limit) = limit {
+            filter = filter.limit(limit);
+        }
+
+        database.fetch_events(filter, &self.options).await
+    }
+
+    /// Returns the creation times and ids of the events that have not been returned yet.
+    fn unseen(&self, events: &Events) -> Vec<(Timestamp, String)> {
+        events
+            .iter()
+            .filter(|event| !self.seen.contains(&event.id))
+            .map(|event| (event.created_at, event.id.to_hex()))
+            .collect()
+    }
+
+    /// Adds the visible records among the fresh events to the buffer, from the newest to the oldest.
+    async fn buffer_records(
+        &mut self,
+        database: &Database,
+        events: &Events,
+        fresh: Vec<(Timestamp, String)>,
+    ) -> Result<(), NostrDBError> {
+        let fresh: HashSet<String> = fresh.into_iter().map(|(_, id)| id).collect();
+        let mut records = database.visible_records(events, &self.options).await?;
+        records.retain(|(_, record)| fresh.contains(&record.event_id));
+        records.sort_by(|(_, a), (_, b)| b.cmp(a));
+        self.buffer.extend(records);
+        Ok(())
+    }
+}
+
+/// Walks the history of a key from the oldest record to the newest, one page at a time.
+/// A tombstone removes all the records before it, so the latest one is looked for first, walking
+/// the history from the newest record without holding it. The aggregated records after it are
+/// then returned, and the records still waiting to be aggregated, one time window at a time.
+/// Records are returned as they were stored, without being decrypted.
+struct ForwardPages {
+    key: String,
+    /// The options of the stream, reading the records as they were stored.
+    options: QueryOptions,
+    /// The latest tombstone of the history, once looked for.
+    start: Option<Option<NostrRecord>>,
+    pages: Option<BacklogPages>,
+    buffer: VecDeque<(PublicKey, NostrRecord)>,
+}
+
+impl ForwardPages {
+    fn new(key: String, options: &QueryOptions) -> Self {
+        Self {
+            key,
+            options: QueryOptions {
+                decrypt: false,
+                ..options.clone()
+            },
+            start: None,
+            pages: None,
+            buffer: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next record of the history, fetching a new page when the buffer is empty.
+    async fn next(
+        &mut self,
+        database: &Database,
+    ) -> Result<Option<(PublicKey, NostrRecord)>, NostrDBError> {
+        loop {
+            if let Some(record) = self.buffer.pop_front() {
+                return Ok(Some(record));
+            }
+
+            let Some(start) = &self.start else {
+                self.start(database).await?;
+                continue;
+            };
+            let Some(pages) = &mut self.pages else {
+                return Ok(None);
+            };
+            let Some(events) = pages.next(database).await? else {
+                return Ok(None);
+            };
+
+            let mut page = Events::new(&Filter::new());
+            page.extend(events);
+            let mut records = database.visible_records(&page, &self.options).await?;
+            records.retain(|(_, record)| is_after(start, record));
+            records.sort_by(|(_, a), (_, b)| a.cmp(b));
+            self.buffer.extend(records);
+        }
+    }
+
+    /// Looks for the latest tombstone, buffers the aggregated records after it, and prepares
+    /// the walk of the records still waiting to be aggregated.
+    async fn start(&mut self, database: &Database) -> Result<(), NostrDBError> {
+        let mut scan = HistoryPages::new(
+            self.key.clone(),
+            &QueryOptions {
+                limit: None,
+                ..self.options.clone()
+            },
+        );
+        while scan.next(database).await?.is_some() {}
+        let start = scan.tombstone;
+
+        // Aggregated records are older than the ones still waiting to be aggregated
+        let aggregated = database
+            .read_authored_aggregates(&self.key, &self.options)
+            .await?;
+        self.buffer.extend(
+            aggregated
+                .into_iter()
+                .filter(|(record, _)| is_after(&start, record))
+                .map(|(record, pubkey)| (pubkey, record)),
+        );
+
+        let range = self.options.range();
+        let removed_at = start.as_ref().map_or(0, |tombstone| tombstone.created_at);
+        let since = range
+            .since
+            .map_or(0, |since| since.as_u64())
+            .max(removed_at);
+        let until = range.until.unwrap_or_else(Timestamp::now);
+        let filter = database.get_filter(&self.key, NOSTR_STORE_KIND).await?;
+        self.pages = Some(
+            BacklogPages::new(filter, Timestamp::from(since), until).with_options(&self.options),
+        );
+        self.start = Some(start);
+        Ok(())
+    }
+}
+
+/// Returns whether the record comes after the given tombstone, if any, and isn't one itself.
+fn is_after(tombstone: &Option<NostrRecord>, record: &NostrRecord) -> bool {
+    !record.tombstone
+        && tombstone
+            .as_ref()
+            .is_none_or(|tombstone| record > tombstone)
+}
+
+impl Database {
+    /// Streams the history of values associated with the given key.
+    /// Unlike `read_history`, records are fetched from the relays one page at a time,
+    /// so that long histories are never held in memory at once.
+    /// Relays return the newest events first: an oldest-first stream first walks the history
+    /// from the newest record to find the latest removal, then fetches the records after it
+    /// one time window at a time, from the oldest.
+    pub fn history_stream<T: Into<String>>(
+        &self,
+        key: T,
+        options: QueryOptions,
+    ) -> BoxStream<'_, Result<NostrRecord, NostrDBError>> {
+        let key = key.into();
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let decrypt = options.decrypt;
+
+        let walk = match options.order {
+            HistoryOrder::NewestFirst => {
+                Walk::NewestFirst(Box::new(HistoryPages::new(key, &options)))
+            }
+            HistoryOrder::OldestFirst => {
+                Walk::OldestFirst(Box::new(ForwardPages::new(key, &options)))
+            }
+        };
+
+        records(self, walk)
+            .take(limit)
+            .then(move |result| async move {
+                let (pubkey, record) = result?;
+                if decrypt {
+                    self.open_record(&pubkey, record).await
+                } else {
+                    Ok(record)
+                }
+            })
+            .boxed()
+    }
+}
+
+/// A walk through the history of a key, in either order.
+enum Walk {
+    NewestFirst(Box<HistoryPages>),
+    OldestFirst(Box<ForwardPages>),
+}
+
+impl Walk {
+    async fn next(
+        &mut self,
+        database: &Database,
+    ) -> Result<Option<(PublicKey, NostrRecord)>, NostrDBError> {
+        match self {
+            Walk::NewestFirst(pages) => pages.next(database).await,
+            Walk::OldestFirst(pages) => pages.next(database).await,
+        }
+    }
+}
+
+/// Turns a walk through a history into a stream of records.
+/// The stream ends after the first error.
+fn records(
+    database: &Database,
+    walk: Walk,
+) -> impl futures::Stream<Item = Result<(PublicKey, NostrRecord), NostrDBError>> + '_ {
+    stream::unfold(Some(walk), move |walk| async move {
+        let mut walk = walk?;
+        match walk.next(database).await {
+            Ok(Some(record)) => Some((Ok(record), Some(walk))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
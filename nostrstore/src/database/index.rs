@@ -0,0 +1,305 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use nostr_sdk::prelude::*;
+
+use super::QueryOptions;
+use super::changes::BacklogPages;
+use super::core::{Database, NOSTR_STORE_AGGREGATE_KIND, NOSTR_STORE_INDEX_KIND, NOSTR_STORE_KIND};
+use super::record::aggregate_records;
+use crate::NostrDBError;
+
+/// Identifier hashed, along with the shard, into the `d` tag of each index event.
+const INDEX_IDENTIFIER: &str = "nostrstore-index";
+
+/// The shards of the index: each key is listed by the index event of the first hex digit of its
+/// `d` tag, so that no index event outgrows the NIP-44 plaintext limit of 65535 bytes.
+const INDEX_SHARDS: &str = "0123456789abcdef";
+
+/// The key index of a database as last fetched or published,
+/// and the changes made while no relay answered, not published yet.
+#[derive(Debug, Default)]
+pub(crate) struct KeyIndex {
+    pub keys: Option<BTreeSet<String>>,
+    /// Whether each changed key was added to the index or removed from it.
+    pending: BTreeMap<String, bool>,
+}
+
+impl KeyIndex {
+    /// Applies the pending changes to the given keys, and returns whether any changed them.
+    fn apply_pending(&self, keys: &mut BTreeSet<String>) -> bool {
+        let mut changed = false;
+        for (key, present) in &self.pending {
+            changed |= match present {
+                true => keys.insert(key.clone()),
+                false => keys.remove(key),
+            };
+        }
+        changed
+    }
+}
+
+impl Database {
+    /// Lists the keys stored in the database, sorted alphabetically.
+    /// The keys are read from the encrypted index events maintained by `store` and `remove`.
+    /// Keys written while no relay answered are listed, though they are not published yet.
+    pub async fn list_keys(&self) -> Result<Vec<String>, NostrDBError> {
+        let filter = self.index_filter();
+        let options = QueryOptions::default();
+        let mut index = self.key_index.lock().await;
+        let mut keys = match self.fetch_answered(filter.clone(), &options).await {
+            Ok(events) => {
+                let keys = self.latest_index(&events).await?;
+                index.keys = Some(keys.clone());
+                keys
+            }
+            // While no relay answers, the index last fetched is listed, or the cached one
+            Err(NostrDBError::Timeout { .. }) => match index.keys.clone() {
+                Some(keys) => keys,
+                None => {
+                    let events = self.fetch_events(filter, &options).await?;
+                    self.latest_index(&events).await?
+                }
+            },
+            Err(e) => return Err(e),
+        };
+        index.apply_pending(&mut keys);
+        Ok(keys.into_iter().collect())
+    }
+
+    /// Lists the keys stored in the database that start with the given prefix.
+    pub async fn list_keys_with_prefix(&self, prefix: &str) -> Result<Vec<String>, NostrDBError> {
+        Ok(self
+            .list_keys()
+            .await?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect())
+    }
+
+    /// Rebuilds the key index from the events stored on the relays.
+    /// Since keys are only published as HMACs, the stored keys are recovered by matching
+    /// the `d` tags of all the stored events against the given candidate keys.
+    /// It returns the keys found, which replace the previous index.
+    pub async fn rebuild_index<I, S>(&self, candidates: I) -> Result<Vec<String>, NostrDBError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let filter = Filter::new()
+            .kinds([
+                Kind::Custom(NOSTR_STORE_KIND),
+                Kind::Custom(NOSTR_STORE_AGGREGATE_KIND),
+            ])
+            .authors(self.authors.iter().copied());
+
+        // The events are fetched a page at a time, however many the relays hold
+        let mut pages = BacklogPages::new(filter, Timestamp::from(0), Timestamp::now());
+        let mut tags = HashSet::new();
+        while let Some(events) = pages.next(self).await? {
+            for event in events {
+                // Aggregates reset by `remove` don't hold any value
+                if event.kind == Kind::Custom(NOSTR_STORE_AGGREGATE_KIND)
+                    && aggregate_records(&event).map_or(true, |records| records.is_empty())
+                {
+                    continue;
+                }
+                if let Some(tag) = event.tags.identifier() {
+                    tags.insert(tag.to_string());
+                }
+            }
+        }
+
+        let mut keys = BTreeSet::new();
+        for candidate in candidates {
+            let candidate = candidate.into();
+            if tags.contains(&self.tag_hash(&candidate)) {
+                keys.insert(candidate);
+            }
+        }
+
+        // Pending changes are dropped, the writes still queued in the outbox being matched too
+        let mut index = self.key_index.lock().await;
+        for (shard, keys) in self.shard_keys(&keys) {
+            self.publish_index(shard, &keys).await?;
+        }
+        let list = keys.iter().cloned().collect();
+        *index = KeyIndex {
+            keys: Some(keys),
+            pending: BTreeMap::new(),
+        };
+        Ok(list)
+    }
+
+    /// Adds the key to the index, publishing the index if the key wasn't already in it.
+    pub(super) async fn index_key(&self, key: &str) {
+        self.update_index([(key.to_string(), true)]).await
+    }
+
+    /// Removes the key from the index, publishing the index if the key was in it.
+    pub(super) async fn unindex_key(&self, key: &str) {
+        self.update_index([(key.to_string(), false)]).await
+    }
+
+    /// Adds each key to the index or removes it from it, as given, publishing the index once.
+    /// The index event of the shard of a key is replaceable, so the latest one on the relays is
+    /// fetched again and the changes merged into it before publishing, keeping the keys indexed
+    /// by other writers. Keys already known to be in the index, or out of it, are left as they are.
+    /// The index follows the writes it lists, so a failing update doesn't fail them: the changes
+    /// are kept and published along with the next ones.
+    pub(super) async fn update_index(&self, changes: impl IntoIterator<Item = (String, bool)>) {
+        let mut index = self.key_index.lock().await;
+        for (key, present) in changes {
+            let known = index
+                .keys
+                .as_ref()
+                .is_some_and(|keys| keys.contains(&key) == present);
+            if !known || index.pending.contains_key(&key) {
+                index.pending.insert(key, present);
+            }
+        }
+        if index.pending.is_empty() {
+            return;
+        }
+
+        if let Err(e) = self.publish_pending_index(&mut index).await {
+            tracing::warn!(error = %e, "Index update deferred.");
+        }
+    }
+
+    /// Publishes the changes of the index made while no relay answered, if any.
+    pub(super) async fn flush_index(&self) -> Result<(), NostrDBError> {
+        let mut index = self.key_index.lock().await;
+        if index.pending.is_empty() {
+            return Ok(());
+        }
+        self.publish_pending_index(&mut index).await
+    }
+
+    /// Merges the pending changes into the latest index events of their shards on the relays,
+    /// and publishes the ones they changed.
+    async fn publish_pending_index(&self, index: &mut KeyIndex) -> Result<(), NostrDBError> {
+        // The whole index is fetched once, then only the shards of the changes
+        let shards: BTreeSet<char> = match index.keys {
+            Some(_) => index.pending.keys().map(|key| self.shard(key)).collect(),
+            None => INDEX_SHARDS.chars().collect(),
+        };
+
+        // The index of the relays is required, so that the few keys written while no relay
+        // answers never replace it
+        let filter = self.shards_filter(&shards);
+        let events = match self.fetch_answered(filter, &QueryOptions::default()).await {
+            Ok(events) => events,
+            Err(e @ NostrDBError::Timeout { .. }) => {
+                tracing::warn!(error = %e, "Index update deferred until a relay answers.");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut keys = self.latest_index(&events).await?;
+        let changed: BTreeSet<char> = index
+            .pending
+            .iter()
+            .filter(|(key, present)| match present {
+                true => keys.insert(key.to_string()),
+                false => keys.remove(key.as_str()),
+            })
+            .map(|(key, _)| self.shard(key))
+            .collect();
+        let mut shard_keys = self.shard_keys(&keys);
+        for shard in &changed {
+            let keys = shard_keys.remove(shard).unwrap_or_default();
+            self.publish_index(*shard, &keys).await?;
+        }
+
+        let known = index.keys.get_or_insert_default();
+        known.retain(|key| !shards.contains(&self.shard(key)));
+        known.extend(keys);
+        index.pending.clear();
+        Ok(())
+    }
+
+    /// Returns the shard of the index listing the given key.
+    fn shard(&self, key: &str) -> char {
+        self.tag_hash(key).chars().next().unwrap_or('0')
+    }
+
+    /// Splits the given keys by the shard of the index listing them.
+    fn shard_keys(&self, keys: &BTreeSet<String>) -> BTreeMap<char, BTreeSet<String>> {
+        let mut shards: BTreeMap<char, BTreeSet<String>> = INDEX_SHARDS
+            .chars()
+            .map(|shard| (shard, BTreeSet::new()))
+            .collect();
+        for key in keys {
+            shards
+                .entry(self.shard(key))
+                .or_default()
+                .insert(key.clone());
+        }
+        shards
+    }
+
+    /// Returns the value of the `d` tag of the index event of the given shard.
+    fn shard_tag(&self, shard: char) -> String {
+        self.tag_hash(&shard_identifier(shard))
+    }
+
+    /// Returns the filter of the index events of every shard.
+    fn index_filter(&self) -> Filter {
+        self.shards_filter(&INDEX_SHARDS.chars().collect())
+    }
+
+    /// Returns the filter of the index events of the given shards.
+    fn shards_filter(&self, shards: &BTreeSet<char>) -> Filter {
+        // Every writer maintains the index, whatever the keys it may write
+        Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_INDEX_KIND))
+            .authors(self.authors.iter().copied())
+            .identifiers(shards.iter().map(|shard| self.shard_tag(*shard)))
+    }
+
+    /// Decrypts the keys listed by the latest index event of each shard among the given ones.
+    async fn latest_index(&self, events: &Events) -> Result<BTreeSet<String>, NostrDBError> {
+        let mut shards = HashSet::new();
+        let mut keys = BTreeSet::new();
+        for event in events.iter() {
+            if shards.insert(event.tags.identifier()) {
+                keys.extend(self.open_index(event).await?);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Returns whether the event is a version of an index shard of this database.
+    pub(super) fn is_index(&self, event: &Event) -> Result<bool, NostrDBError> {
+        Ok(event.kind == Kind::Custom(NOSTR_STORE_INDEX_KIND)
+            && INDEX_SHARDS
+                .chars()
+                .any(|shard| event.tags.identifier() == Some(self.shard_tag(shard).as_str())))
+    }
+
+    /// Decrypts the keys listed by an index event.
+    pub(super) async fn open_index(&self, event: &Event) -> Result<BTreeSet<String>, NostrDBError> {
+        let content = self.nip44_decrypt(&event.pubkey, &event.content).await?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Publishes the encrypted index event of the given shard, replacing the previous one.
+    async fn publish_index(
+        &self,
+        shard: char,
+        keys: &BTreeSet<String>,
+    ) -> Result<(), NostrDBError> {
+        let encrypted = self.nip44_encrypt(&serde_json::to_string(keys)?).await?;
+        let builder = EventBuilder::new(Kind::Custom(NOSTR_STORE_INDEX_KIND), encrypted)
+            .tag(self.d_tag(&shard_identifier(shard)));
+
+        self.send_event(builder).await?;
+        Ok(())
+    }
+}
+
+/// Returns the identifier hashed into the `d` tag of the index event of the given shard.
+fn shard_identifier(shard: char) -> String {
+    format!("{INDEX_IDENTIFIER}-{shard}")
+}
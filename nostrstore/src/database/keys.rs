@@ -0,0 +1,264 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use nostr_sdk::hashes::Hash as _;
+use nostr_sdk::nips::nip44::v2::{self, ConversationKey};
+use nostr_sdk::prelude::*;
+use nostr_sdk::util::hkdf;
+use sha2::Sha256;
+
+use super::core::{Database, NOSTR_STORE_KEY_KIND};
+use super::{QueryOptions, ReadConsistency};
+use crate::NostrDBError;
+
+/// Identifier of the event holding the storage master key, encrypted to the signer.
+const STORAGE_KEY_IDENTIFIER: &str = "nostrstore-storage-key";
+
+/// Salt of the HKDF extraction of the subkeys from the storage master key.
+const HKDF_SALT: &[u8] = b"nostrstore-v1";
+
+/// The smallest NIP-44 payload: version, nonce, one padded block and MAC.
+const MIN_PAYLOAD_SIZE: usize = 1 + 32 + 34 + 32;
+
+/// How the keys hashing the `d` tags and encrypting the values are obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyScheme {
+    /// Tags are hashed with the secret key of the identity, and values are encrypted
+    /// to the identity with NIP-44. It requires local keys.
+    Identity,
+    /// A random storage master key is published once, encrypted to the identity, and subkeys
+    /// are derived from it with HKDF: one hashing the tags and one encrypting the values,
+    /// and another pair for each bucket. A leaked tag key reveals nothing of the values,
+    /// and values are encrypted without a round-trip to the signer.
+    Derived,
+}
+
+/// The keys hashing the `d` tags and encrypting the values of a database or bucket.
+#[derive(Clone)]
+pub(crate) struct Keyring {
+    /// The secret key of the identity, or the storage master key, which buckets derive from.
+    root: [u8; 32],
+    scheme: KeyScheme,
+    tag: [u8; 32],
+    /// The master keys the content keys are derived from, the current one last.
+    masters: Vec<[u8; 32]>,
+    /// The keys encrypting the values, the current one last, or none to encrypt them to the identity.
+    content: Vec<[u8; 32]>,
+    /// The name of the bucket the keys are scoped to, if any.
+    bucket: Option<String>,
+}
+
+impl Keyring {
+    /// The keys of the identity scheme: the secret key of the identity hashes the tags.
+    pub fn identity(secret_key: [u8; 32]) -> Self {
+        Self {
+            root: secret_key,
+            scheme: KeyScheme::Identity,
+            tag: secret_key,
+            masters: Vec::new(),
+            content: Vec::new(),
+            bucket: None,
+        }
+    }
+
+    /// The keys derived from the storage master key.
+    pub fn derived(master: [u8; 32]) -> Self {
+        Self::shared(master, vec![master])
+    }
+
+    /// The keys derived from the successive master keys of a store: the tags are hashed with
+    /// a key derived from the root, and the values encrypted with a key derived from the
+    /// current master key. Values encrypted with the previous ones can still be decrypted.
+    pub fn shared(root: [u8; 32], masters: Vec<[u8; 32]>) -> Self {
+        Self {
+            root,
+            scheme: KeyScheme::Derived,
+            tag: derive(&root, "tag"),
+            content: masters
+                .iter()
+                .map(|master| derive(master, "content"))
+                .collect(),
+            masters,
+            bucket: None,
+        }
+    }
+
+    /// Returns the keys of the bucket with the given name.
+    pub fn bucket(&self, name: &str) -> Self {
+        match self.scheme {
+            KeyScheme::Identity => Self {
+                tag: hmac(&self.root, &format!("bucket:{}", name)),
+                bucket: Some(name.to_string()),
+                ..self.clone()
+            },
+            KeyScheme::Derived => Self {
+                tag: derive(&self.root, &format!("bucket/{}/tag", name)),
+                content: self
+                    .masters
+                    .iter()
+                    .map(|master| derive(master, &format!("bucket/{}/content", name)))
+                    .collect(),
+                bucket: Some(name.to_string()),
+                ..self.clone()
+            },
+        }
+    }
+
+    /// Returns the given keys of another root, scoped to the same bucket as these keys.
+    pub fn rebase(&self, root: Keyring) -> Self {
+        match &self.bucket {
+            Some(name) => root.bucket(name),
+            None => root,
+        }
+    }
+
+    pub fn scheme(&self) -> KeyScheme {
+        self.scheme
+    }
+
+    /// Returns whether the keys may have stored aggregates under the key in clear, as databases
+    /// of the identity scheme did before the `d` tags of aggregates were hashed.
+    pub fn has_legacy_aggregates(&self) -> bool {
+        self.scheme == KeyScheme::Identity && self.bucket.is_none()
+    }
+
+    /// Returns the secret key of the identity, or the storage master key, which buckets derive from.
+    pub fn root(&self) -> &[u8; 32] {
+        &self.root
+    }
+
+    /// Returns the key the `d` tags are hashed with.
+    pub fn tag(&self) -> &[u8; 32] {
+        &self.tag
+    }
+}
+
+impl Database {
+    /// Encrypts a value with the content key, or to the identity with the signer.
+    pub(super) async fn nip44_encrypt(&self, content: &str) -> Result<String, NostrDBError> {
+        match self.keyring.content.last() {
+            Some(key) => {
+                let payload = v2::encrypt_to_bytes(&ConversationKey::new(*key), content.as_bytes())
+                    .map_err(|e| NostrDBError::EncryptionError(SignerError::backend(e)))?;
+                Ok(BASE64.encode(payload))
+            }
+            None => self
+                .signer
+                .nip44_encrypt(&self.public_key, content)
+                .await
+                .map_err(NostrDBError::EncryptionError),
+        }
+    }
+
+    /// Decrypts a value with the content keys, or with the signer.
+    /// Values the content keys can't decrypt, such as the ones stored with the identity scheme,
+    /// are decrypted with the signer.
+    pub(super) async fn nip44_decrypt(
+        &self,
+        pubkey: &PublicKey,
+        content: &str,
+    ) -> Result<String, NostrDBError> {
+        if !self.keyring.content.is_empty() {
+            let payload = BASE64
+                .decode(content)
+                .ok()
+                .filter(|payload| payload.len() >= MIN_PAYLOAD_SIZE);
+            // The MAC of the payload only matches the key it was encrypted with
+            let decrypted = payload.and_then(|payload| {
+                self.keyring.content.iter().rev().find_map(|key| {
+                    v2::decrypt_to_bytes(&ConversationKey::new(*key), &payload)
+                        .ok()
+                        .and_then(|plaintext| String::from_utf8(plaintext).ok())
+                })
+            });
+            if let Some(decrypted) = decrypted {
+                return Ok(decrypted);
+            }
+        }
+
+        self.signer
+            .nip44_decrypt(pubkey, content)
+            .await
+            .map_err(NostrDBError::DecryptionError)
+    }
+
+    /// Loads the storage master key of the derived key scheme.
+    /// The key is generated and published, encrypted to the signer, the first time the identity
+    /// opens a store with the derived scheme. A key is only generated once every read relay of
+    /// the pool answered without one, since replacing the key would make the values stored
+    /// with it unreadable: while some relays don't answer, it returns a `Timeout` error instead.
+    /// When two databases publish one at the same time, both keep the one the relays kept.
+    pub(super) async fn load_storage_key(&self) -> Result<[u8; 32], NostrDBError> {
+        self.adopt_storage_key(rand::random()).await
+    }
+
+    /// Loads the storage master key of the derived key scheme, like `load_storage_key`, but
+    /// publishes the given key, encrypted to the signer, when the identity has none.
+    pub(super) async fn adopt_storage_key(&self, key: [u8; 32]) -> Result<[u8; 32], NostrDBError> {
+        if let Some(key) = self.fetch_storage_key(&QueryOptions::default()).await? {
+            return Ok(key);
+        }
+        let all = QueryOptions::default().with_consistency(ReadConsistency::All);
+        if let Some(key) = self.fetch_storage_key(&all).await? {
+            return Ok(key);
+        }
+
+        let encrypted = self
+            .signer
+            .nip44_encrypt(&self.public_key, &hex::encode(key))
+            .await
+            .map_err(NostrDBError::EncryptionError)?;
+        let builder = EventBuilder::new(Kind::Custom(NOSTR_STORE_KEY_KIND), encrypted)
+            .tag(Tag::identifier(STORAGE_KEY_IDENTIFIER));
+        self.send_event(builder).await?;
+
+        Ok(self
+            .fetch_storage_key(&QueryOptions::default())
+            .await?
+            .unwrap_or(key))
+    }
+
+    /// Fetches and decrypts the storage master key published by the signer, if any.
+    async fn fetch_storage_key(
+        &self,
+        options: &QueryOptions,
+    ) -> Result<Option<[u8; 32]>, NostrDBError> {
+        let filter = Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_KEY_KIND))
+            .author(self.public_key)
+            .identifier(STORAGE_KEY_IDENTIFIER);
+        let events = self.fetch_events(filter, options).await?;
+
+        let Some(event) = events.first() else {
+            return Ok(None);
+        };
+        let content = self
+            .signer
+            .nip44_decrypt(&event.pubkey, &event.content)
+            .await
+            .map_err(NostrDBError::DecryptionError)?;
+        let key = hex::decode(content)
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| {
+                NostrDBError::DatabaseError("Storage key event is malformed".to_string())
+            })?;
+        Ok(Some(key))
+    }
+}
+
+/// Derives the subkey with the given purpose from the storage master key.
+fn derive(master: &[u8; 32], purpose: &str) -> [u8; 32] {
+    let prk = hkdf::extract(HKDF_SALT, master);
+    let info = format!("nostrstore/{}", purpose);
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hkdf::expand(prk.as_byte_array(), info.as_bytes(), 32));
+    key
+}
+
+/// Computes the HMAC-SHA256 of the message with the given key.
+pub(super) fn hmac(key: &[u8; 32], message: &str) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(message.as_bytes());
+    mac.finalize().into_bytes().into()
+}
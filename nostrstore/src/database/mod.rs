@@ -1,9 +1,41 @@
+pub mod acl;
+pub mod batch;
+pub mod bucket;
 pub mod builder;
+pub mod cache;
+pub mod changes;
+pub mod chunk;
 pub mod core;
+#[cfg(feature = "file-cache")]
+mod file_cache;
+pub mod history;
+mod index;
+mod keys;
+pub mod outbox;
 pub mod query;
+pub mod quorum;
 pub mod record;
+pub mod rotation;
+pub mod shared;
+pub mod sync;
+pub mod version;
+pub mod watch;
 
+pub use acl::{ACL_KEY, Acl};
+pub use batch::WriteBatch;
+pub use bucket::Bucket;
 pub use builder::DatabaseBuilder;
+pub use cache::DEFAULT_CACHE_OVERLAP;
+pub use changes::{CHANGES_PAGE_SIZE, Change, ChangeKind};
+pub use chunk::DEFAULT_CHUNK_SIZE;
 pub use core::Database;
-pub use query::QueryOptions;
-pub use record::NostrRecord;
+pub use history::HISTORY_PAGE_SIZE;
+pub use keys::KeyScheme;
+pub use outbox::PendingWrite;
+pub use query::{DEFAULT_TIMEOUT, HistoryOrder, QueryOptions};
+pub use quorum::{ConsistencyReport, ReadConsistency, StoreReceipt, WriteQuorum};
+pub use record::{NostrRecord, TypedRecord};
+pub use rotation::RotationReport;
+pub use shared::{MemberRole, SharedDatabase};
+pub use sync::{RelaySync, SyncReport};
+pub use version::{Version, Versioned};
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use super::core::Database;
+use super::quorum::StoreReceipt;
+use crate::NostrDBError;
+
+/// Name of the file of an outbox directory holding the queued events.
+const OUTBOX_FILE: &str = "outbox.json";
+
+/// Prefixes of the NIP-01 `OK` messages of the relays rejecting an event for good, as opposed to
+/// asking to send it again later.
+const PERMANENT_REJECTIONS: [&str; 5] = ["invalid", "pow", "blocked", "restricted", "mute"];
+
+/// A write queued in the outbox, waiting for a relay to accept it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingWrite {
+    pub event: Event,
+    /// The number of times the event was sent to the relays without any accepting it.
+    pub attempts: u32,
+    /// Why the last attempt failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// Whether a relay rejected the event for good, such as an event that expired while queued.
+    /// Rejected writes are no longer sent and no longer hold the queue, until they are discarded.
+    #[serde(default)]
+    pub rejected: bool,
+}
+
+impl PendingWrite {
+    /// Returns the id of the queued event.
+    pub fn id(&self) -> &EventId {
+        &self.event.id
+    }
+}
+
+/// A durable queue of the signed events that no relay accepted, persisted in a directory.
+/// Events are published in the order they were queued, and a write made while the outbox
+/// is not empty waits behind the ones queued before it. The events rejected for good are left
+/// in the queue, skipped, until they are discarded.
+#[derive(Debug)]
+pub(crate) struct Outbox {
+    path: PathBuf,
+    pending: Mutex<Vec<PendingWrite>>,
+}
+
+impl Outbox {
+    /// Opens the outbox in the given directory, creating it if it doesn't exist.
+    pub async fn open<P: AsRef<Path>>(path: P) -> Result<Self, NostrDBError> {
+        fs::create_dir_all(&path).await.map_err(outbox_error)?;
+        let path = path.as_ref().join(OUTBOX_FILE);
+        let pending = match fs::read_to_string(&path).await {
+            Ok(pending) => serde_json::from_str(&pending)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(outbox_error(e)),
+        };
+
+        Ok(Self {
+            path,
+            pending: Mutex::new(pending),
+        })
+    }
+
+    /// Sends the event to the relays, after the events queued before it.
+    /// The event is queued when writes are still pending or when no relay answers it. An event
+    /// rejected for good is not queued, and the receipt lists the rejections.
+    pub async fn send(
+        &self,
+        relay_pool: &RelayPool,
+        event: &Event,
+    ) -> Result<StoreReceipt, NostrDBError> {
+        let mut pending = self.pending.lock().await;
+        self.publish_pending(relay_pool, &mut pending).await?;
+
+        let mut receipt = StoreReceipt {
+            event_id: event.id,
+            accepted: Default::default(),
+            rejected: HashMap::new(),
+            queued: false,
+        };
+        let last_error = if pending.iter().all(|write| write.rejected) {
+            match relay_pool.send_event(event).await {
+                Ok(output) if !output.success.is_empty() || is_rejected(&output.failed) => {
+                    receipt.accepted = output.success;
+                    receipt.rejected = output.failed;
+                    return Ok(receipt);
+                }
+                Ok(output) => {
+                    let error = rejection(&output.failed);
+                    receipt.rejected = output.failed;
+                    error
+                }
+                Err(e) => e.to_string(),
+            }
+        } else {
+            "Queued behind pending writes".to_string()
+        };
+
+        tracing::warn!(id = %event.id, error = %last_error, "Write queued in the outbox.");
+        pending.push(PendingWrite {
+            event: event.clone(),
+            attempts: 0,
+            last_error: Some(last_error),
+            rejected: false,
+        });
+        self.persist(&pending).await?;
+        receipt.queued = true;
+        Ok(receipt)
+    }
+
+    /// Publishes the queued events, in order, until one is answered by no relay.
+    /// The events rejected for good are marked as such and skipped.
+    /// Returns the number of events published.
+    pub async fn flush(&self, relay_pool: &RelayPool) -> Result<usize, NostrDBError> {
+        let mut pending = self.pending.lock().await;
+        self.publish_pending(relay_pool, &mut pending).await
+    }
+
+    async fn publish_pending(
+        &self,
+        relay_pool: &RelayPool,
+        pending: &mut Vec<PendingWrite>,
+    ) -> Result<usize, NostrDBError> {
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let mut published = 0;
+        let mut position = 0;
+        while let Some(write) = pending.get_mut(position) {
+            if write.rejected {
+                position += 1;
+                continue;
+            }
+
+            let (error, rejected) = match relay_pool.send_event(&write.event).await {
+                Ok(output) if !output.success.is_empty() => (None, false),
+                Ok(output) => (Some(rejection(&output.failed)), is_rejected(&output.failed)),
+                Err(e) => (Some(e.to_string()), false),
+            };
+            let Some(error) = error else {
+                pending.remove(position);
+                published += 1;
+                continue;
+            };
+            write.attempts += 1;
+            write.last_error = Some(error);
+            if !rejected {
+                break;
+            }
+            tracing::warn!(id = %write.event.id, error = ?write.last_error, "Queued write rejected by the relays.");
+            write.rejected = true;
+            position += 1;
+        }
+
+        self.persist(pending).await?;
+        Ok(published)
+    }
+
+    /// Returns the queued events matching the filter, leaving out the ones rejected for good.
+    pub async fn matching(&self, filter: &Filter) -> Events {
+        let mut events = Events::new(filter);
+        events.extend(
+            self.pending
+                .lock()
+                .await
+                .iter()
+                .filter(|write| !write.rejected && filter.match_event(&write.event))
+                .map(|write| write.event.clone()),
+        );
+        events
+    }
+
+    pub async fn pending(&self) -> Vec<PendingWrite> {
+        self.pending.lock().await.clone()
+    }
+
+    /// Removes the queued event of the given id. Returns whether it was queued.
+    pub async fn discard(&self, id: &EventId) -> Result<bool, NostrDBError> {
+        let mut pending = self.pending.lock().await;
+        let Some(position) = pending.iter().position(|write| write.id() == id) else {
+            return Ok(false);
+        };
+        pending.remove(position);
+        self.persist(&pending).await?;
+        Ok(true)
+    }
+
+    /// Writes the queue to a temporary file, then moves it over the outbox file,
+    /// so that a crash never leaves a partial queue behind.
+    async fn persist(&self, pending: &[PendingWrite]) -> Result<(), NostrDBError> {
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(pending)?)
+            .await
+            .map_err(outbox_error)?;
+        fs::rename(&tmp, &self.path).await.map_err(outbox_error)
+    }
+
+    /// Flushes the outbox at the given interval in a background task,
+    /// until the outbox is dropped along with the database.
+    pub fn spawn_flush(outbox: &Arc<Self>, relay_pool: RelayPool, interval: Duration) {
+        let outbox: Weak<Self> = Arc::downgrade(outbox);
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let Some(outbox) = outbox.upgrade() else {
+                    break;
+                };
+                match outbox.flush(&relay_pool).await {
+                    Ok(0) => {}
+                    Ok(published) => tracing::info!(published, "Outbox flushed."),
+                    Err(e) => tracing::warn!(error = %e, "Failed to flush the outbox."),
+                }
+            }
+        });
+    }
+}
+
+impl Database {
+    /// Publishes the writes queued in the outbox while no relay was reachable, in order.
+    /// It stops at the first write still answered by no relay, and returns the number of writes published.
+    /// Writes that a relay rejects for good, like events that expired meanwhile, are skipped and
+    /// left in `pending_writes` until discarded. The keys written meanwhile are then added to the key index.
+    pub async fn flush_outbox(&self) -> Result<usize, NostrDBError> {
+        let Some(outbox) = &self.outbox else {
+            return Ok(0);
+        };
+        let published = outbox.flush(&self.relay_pool).await?;
+        self.flush_index().await?;
+        Ok(published)
+    }
+
+    /// Returns the writes queued in the outbox, from the oldest to the newest, along with the
+    /// ones rejected for good.
+    pub async fn pending_writes(&self) -> Vec<PendingWrite> {
+        match &self.outbox {
+            Some(outbox) => outbox.pending().await,
+            None => Vec::new(),
+        }
+    }
+
+    /// Removes the write of the given event from the outbox, whether it is still queued or was
+    /// rejected for good, so that it is never published. Returns whether it was in the outbox.
+    pub async fn discard_pending_write(&self, id: &EventId) -> Result<bool, NostrDBError> {
+        match &self.outbox {
+            Some(outbox) => outbox.discard(id).await,
+            None => Ok(false),
+        }
+    }
+}
+
+/// Describes why no relay accepted an event.
+fn rejection(failed: &HashMap<RelayUrl, String>) -> String {
+    if failed.is_empty() {
+        return "No relay accepted the event".to_string();
+    }
+    let mut reasons: Vec<String> = failed
+        .iter()
+        .map(|(url, reason)| format!("{}: {}", url, reason))
+        .collect();
+    reasons.sort();
+    reasons.join(", ")
+}
+
+/// Returns whether a relay rejected the event for good, rather than not answering or
+/// asking to send it again later.
+fn is_rejected(failed: &HashMap<RelayUrl, String>) -> bool {
+    failed.values().any(|reason| {
+        reason
+            .split_once(':')
+            .is_some_and(|(prefix, _)| PERMANENT_REJECTIONS.contains(&prefix.trim()))
+    })
+}
+
+fn outbox_error<E: std::fmt::Display>(e: E) -> NostrDBError {
+    NostrDBError::DatabaseError(format!("Outbox error: {}", e))
+}
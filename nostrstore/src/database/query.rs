@@ -1,11 +1,42 @@
+use std::time::Duration;
+
+use nostr_sdk::prelude::ReqExitPolicy;
+use nostr_sdk::{Filter, Timestamp};
+
+use super::quorum::ReadConsistency;
+
+/// Default time given to the relays to answer a request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Query options for database queries.
 /// This struct allows you to specify options for querying the database,
 /// such as whether to decrypt the data and the maximum number of results to possibly aggregate.
-/// It is used in the `read_history` method of the `Database` struct.
+/// It is used in the `read_history` and `history_stream` methods of the `Database` struct.
 #[derive(Clone)]
 pub struct QueryOptions {
     pub decrypt: bool,
     pub aggregate_count: usize,
+    /// The maximum number of records to return, taken in the order of the query.
+    pub limit: Option<usize>,
+    /// Only the records created at or after this time are returned.
+    pub since: Option<Timestamp>,
+    /// Only the records created at or before this time are returned.
+    pub until: Option<Timestamp>,
+    pub order: HistoryOrder,
+    /// The time given to the relays to answer, overriding the timeout of the database.
+    pub timeout: Option<Duration>,
+    /// When the relays stop sending events, overriding the exit policy of the database.
+    pub exit_policy: Option<ReqExitPolicy>,
+    /// How many relays must answer, and agree on the events they return.
+    pub consistency: ReadConsistency,
+}
+
+/// The order in which the records of a history are returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HistoryOrder {
+    #[default]
+    OldestFirst,
+    NewestFirst,
 }
 
 impl Default for QueryOptions {
@@ -13,6 +44,13 @@ impl Default for QueryOptions {
         Self {
             decrypt: true,
             aggregate_count: 1000,
+            limit: None,
+            since: None,
+            until: None,
+            order: HistoryOrder::default(),
+            timeout: None,
+            exit_policy: None,
+            consistency: ReadConsistency::default(),
         }
     }
 }
@@ -22,6 +60,97 @@ impl QueryOptions {
         Self {
             decrypt,
             aggregate_count,
+            ..Self::default()
+        }
+    }
+
+    /// Sets the maximum number of records to return.
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Sets the time from which records are returned.
+    pub fn with_since(mut self, since: Timestamp) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Sets the time up to which records are returned.
+    pub fn with_until(mut self, until: Timestamp) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// Sets the order in which records are returned.
+    pub fn with_order(mut self, order: HistoryOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Sets the time given to the relays to answer.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets when the relays stop sending events.
+    pub fn with_exit_policy(mut self, exit_policy: ReqExitPolicy) -> Self {
+        self.exit_policy = Some(exit_policy);
+        self
+    }
+
+    /// Sets how many relays must answer, and agree on the events they return.
+    pub fn with_consistency(mut self, consistency: ReadConsistency) -> Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// Returns the options reading the records as they were stored, without decrypting them.
+    pub(crate) fn stored() -> Self {
+        Self {
+            decrypt: false,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the range of creation times selected by the options.
+    pub(crate) fn range(&self) -> TimeRange {
+        TimeRange {
+            since: self.since,
+            until: self.until,
+        }
+    }
+}
+
+/// A range of creation times, used to restrict the records read from the database.
+/// The bounds are inclusive, as in Nostr filters.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TimeRange {
+    pub since: Option<Timestamp>,
+    pub until: Option<Timestamp>,
+}
+
+impl TimeRange {
+    /// Returns whether the range includes all the records.
+    pub fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    /// Returns whether the range includes the given creation time.
+    pub fn contains(&self, created_at: u64) -> bool {
+        self.since.is_none_or(|since| created_at >= since.as_u64())
+            && self.until.is_none_or(|until| created_at <= until.as_u64())
+    }
+
+    /// Restricts the filter to the range.
+    pub fn apply(&self, mut filter: Filter) -> Filter {
+        if let Some(since) = self.since {
+            filter = filter.since(since);
+        }
+        if let Some(until) = self.until {
+            filter = filter.until(until);
         }
+        filter
     }
 }
@@ -0,0 +1,182 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use nostr_sdk::prelude::*;
+
+use super::QueryOptions;
+use super::core::{Database, NOSTR_STORE_AGGREGATE_KIND, NOSTR_STORE_KIND};
+use crate::NostrDBError;
+
+/// The number of relays that must accept an event for a write to succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteQuorum {
+    /// Every write relay of the pool.
+    All,
+    /// More than half of the write relays of the pool.
+    Majority,
+    /// At least the given number of relays.
+    AtLeast(usize),
+}
+
+impl Default for WriteQuorum {
+    /// A write succeeds as soon as one relay accepted it.
+    fn default() -> Self {
+        Self::AtLeast(1)
+    }
+}
+
+impl WriteQuorum {
+    /// Returns the number of relays that must accept an event sent to the given number of relays.
+    pub fn required(&self, relays: usize) -> usize {
+        match *self {
+            Self::All => relays,
+            Self::Majority => relays / 2 + 1,
+            Self::AtLeast(count) => count,
+        }
+    }
+}
+
+/// The outcome of publishing an event: which relays accepted it, and which rejected it and why.
+#[derive(Debug, Clone)]
+pub struct StoreReceipt {
+    pub event_id: EventId,
+    pub accepted: HashSet<RelayUrl>,
+    pub rejected: HashMap<RelayUrl, String>,
+    /// Whether the event was queued in the outbox, to be published once a relay accepts it.
+    pub queued: bool,
+}
+
+impl StoreReceipt {
+    /// Returns whether the event was accepted by enough relays to meet the quorum.
+    pub fn meets(&self, quorum: WriteQuorum) -> bool {
+        self.accepted.len() >= quorum.required(self.accepted.len() + self.rejected.len())
+    }
+}
+
+/// The number of relays that must answer a read, and agree on the events they return.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadConsistency {
+    /// The events returned by any relay are merged.
+    #[default]
+    One,
+    /// More than half of the read relays of the pool must answer with every event returned.
+    Quorum,
+    /// Every read relay of the pool must answer with every event returned.
+    All,
+}
+
+impl ReadConsistency {
+    /// Returns the number of relays that must answer a read sent to the given number of relays.
+    pub fn required(&self, relays: usize) -> usize {
+        match *self {
+            Self::One => 1,
+            Self::Quorum => relays / 2 + 1,
+            Self::All => relays,
+        }
+    }
+}
+
+/// The events returned by each relay for a request, and the relays that didn't answer in time.
+#[derive(Default)]
+pub(crate) struct RelayFetch {
+    pub answered: HashMap<RelayUrl, Events>,
+    pub late: Vec<RelayUrl>,
+}
+
+impl RelayFetch {
+    /// Returns the number of relays the request was sent to.
+    pub fn relays(&self) -> usize {
+        self.answered.len() + self.late.len()
+    }
+
+    /// Returns, for each relay that answered without some of the given events, the ids it is missing.
+    /// Replaced versions of an aggregate don't count, only its latest version does.
+    pub fn behind(&self, events: &Events) -> HashMap<RelayUrl, Vec<EventId>> {
+        let mut latest: HashMap<(Kind, Option<&str>), &Event> = HashMap::new();
+        for event in events.iter().filter(|event| event.kind.is_addressable()) {
+            let latest = latest
+                .entry((event.kind, event.tags.identifier()))
+                .or_insert(event);
+            if event.created_at > latest.created_at {
+                *latest = event;
+            }
+        }
+        let current: Vec<&Event> = events
+            .iter()
+            .filter(|event| {
+                !event.kind.is_addressable()
+                    || latest
+                        .get(&(event.kind, event.tags.identifier()))
+                        .is_some_and(|latest| latest.id == event.id)
+            })
+            .collect();
+
+        self.answered
+            .iter()
+            .map(|(url, fetched)| {
+                let missing: Vec<EventId> = current
+                    .iter()
+                    .filter(|event| !fetched.contains(event))
+                    .map(|event| event.id)
+                    .collect();
+                (url.clone(), missing)
+            })
+            .filter(|(_, missing)| !missing.is_empty())
+            .collect()
+    }
+}
+
+/// Returns the sorted names of the given relays.
+pub(crate) fn relay_names<'a>(relays: impl Iterator<Item = &'a RelayUrl>) -> Vec<String> {
+    let mut names: Vec<String> = relays.map(|url| url.to_string()).collect();
+    names.sort();
+    names
+}
+
+/// How the relays of the pool agree on the events of a key.
+#[derive(Debug, Clone)]
+pub struct ConsistencyReport {
+    pub key: String,
+    /// The ids of the events of the key returned by any relay.
+    pub events: BTreeSet<EventId>,
+    /// For each relay that is behind, the ids of the events it is missing.
+    pub behind: HashMap<RelayUrl, Vec<EventId>>,
+    /// The relays that didn't answer in time.
+    pub unreachable: Vec<RelayUrl>,
+}
+
+impl ConsistencyReport {
+    /// Returns whether every relay answered with every event of the key.
+    pub fn is_consistent(&self) -> bool {
+        self.behind.is_empty() && self.unreachable.is_empty()
+    }
+}
+
+impl Database {
+    /// Compares the events of the given key returned by each relay, records and aggregate alike,
+    /// and reports the relays that are missing some of them.
+    pub async fn consistency_report<T: Into<String>>(
+        &self,
+        key: T,
+    ) -> Result<ConsistencyReport, NostrDBError> {
+        let key_str = key.into();
+        let filter = self
+            .get_filter(&key_str, NOSTR_STORE_KIND)
+            .await?
+            .kind(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND));
+        let fetch = self
+            .fetch_by_relay(filter.clone(), &QueryOptions::default())
+            .await?;
+
+        let mut events = Events::new(&filter);
+        for fetched in fetch.answered.values() {
+            events = events.merge(fetched.clone());
+        }
+
+        Ok(ConsistencyReport {
+            key: key_str,
+            events: events.iter().map(|event| event.id).collect(),
+            behind: fetch.behind(&events),
+            unreachable: fetch.late,
+        })
+    }
+}
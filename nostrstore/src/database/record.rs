@@ -1,6 +1,18 @@
 use nostr_sdk::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use super::batch::{BATCH_TAG, TOMBSTONE_TAG};
+use super::chunk::CHUNK_TAG;
+use super::version::PARENT_TAG;
+use crate::NostrDBError;
+use crate::codec::CodecKind;
+
+/// Name of the tag holding the identifier of the codec used to encode a value.
+pub(crate) const CODEC_TAG: &str = "codec";
+
+/// Name of the tag holding the identifier of the algorithm used to compress a value.
+pub(crate) const COMPRESSION_TAG: &str = "compression";
+
 /// A struct representing a Database record in Nostr.
 /// It's used primarily when aggregating events in one single event.
 /// The content is encrypted using the NIP-44 encryption scheme.
@@ -9,6 +21,30 @@ pub struct NostrRecord {
     pub created_at: u64,
     pub content: String,
     pub event_id: String,
+    /// Identifier of the codec the content was encoded with, if it was stored with `put`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<String>,
+    /// Identifier of the algorithm the content was compressed with, while it is still compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// Ids of the chunk events the content was split into, if it was too large for a single event.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// NIP-40 expiration timestamp of the record, if it was stored with a time-to-live.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiration: Option<u64>,
+    /// Id of the write batch the record was committed with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    /// Position of the record within its write batch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_index: Option<u32>,
+    /// Whether the record marks the removal of all the previous values of its key.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tombstone: bool,
+    /// Ids of the records this record was written on top of by `compare_and_swap`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parents: Vec<String>,
 }
 
 impl NostrRecord {
@@ -17,8 +53,21 @@ impl NostrRecord {
             created_at,
             content,
             event_id,
+            codec: None,
+            compression: None,
+            chunks: Vec::new(),
+            expiration: None,
+            batch: None,
+            batch_index: None,
+            tombstone: false,
+            parents: Vec::new(),
         }
     }
+
+    /// Returns whether the record expired at the given unix timestamp.
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expiration.is_some_and(|expiration| expiration <= now)
+    }
 }
 
 impl PartialEq for NostrRecord {
@@ -30,21 +79,84 @@ impl Eq for NostrRecord {}
 
 impl PartialOrd for NostrRecord {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.created_at.cmp(&other.created_at))
+        Some(self.cmp(other))
     }
 }
 impl Ord for NostrRecord {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.created_at.cmp(&other.created_at)
+        // Records of a write batch share their creation time, and are ordered by their position.
+        // Other records created in the same second are ordered by id so that none of them is lost.
+        self.created_at
+            .cmp(&other.created_at)
+            .then_with(|| self.batch_index.cmp(&other.batch_index))
+            .then_with(|| self.event_id.cmp(&other.event_id))
     }
 }
 
 impl From<&Event> for NostrRecord {
     fn from(event: &Event) -> Self {
+        let batch_tag = event.tags.find(TagKind::custom(BATCH_TAG));
         Self {
             created_at: event.created_at.as_u64(),
             content: event.content.clone(),
             event_id: event.id.to_string(),
+            codec: event
+                .tags
+                .find(TagKind::custom(CODEC_TAG))
+                .and_then(|tag| tag.content())
+                .map(str::to_string),
+            compression: event
+                .tags
+                .find(TagKind::custom(COMPRESSION_TAG))
+                .and_then(|tag| tag.content())
+                .map(str::to_string),
+            chunks: event
+                .tags
+                .filter(TagKind::custom(CHUNK_TAG))
+                .filter_map(|tag| tag.content())
+                .map(str::to_string)
+                .collect(),
+            expiration: event.tags.expiration().map(Timestamp::as_u64),
+            batch: batch_tag.and_then(|tag| tag.as_slice().get(1)).cloned(),
+            batch_index: batch_tag
+                .and_then(|tag| tag.as_slice().get(2))
+                .and_then(|index| index.parse().ok()),
+            tombstone: event.tags.find(TagKind::custom(TOMBSTONE_TAG)).is_some(),
+            parents: event
+                .tags
+                .filter(TagKind::custom(PARENT_TAG))
+                .filter_map(|tag| tag.content())
+                .map(str::to_string)
+                .collect(),
         }
     }
 }
+
+/// Returns the codec named by the codec tag of the event.
+/// Events without a codec tag were encoded as JSON.
+pub(crate) fn event_codec(event: &Event) -> Result<CodecKind, NostrDBError> {
+    match event
+        .tags
+        .find(TagKind::custom(CODEC_TAG))
+        .and_then(|tag| tag.content())
+    {
+        Some(id) => CodecKind::from_id(id)
+            .ok_or_else(|| NostrDBError::CodecError(format!("unsupported codec '{}'", id))),
+        None => Ok(CodecKind::Json),
+    }
+}
+
+/// Decodes the records held by an aggregate event, with the codec it was encoded with.
+pub(crate) fn aggregate_records(event: &Event) -> Result<Vec<NostrRecord>, NostrDBError> {
+    event_codec(event)?
+        .decode(&event.content)
+        .map_err(|e| NostrDBError::CodecError(e.to_string()))
+}
+
+/// A decoded Database record, as returned by the typed read methods.
+#[derive(Debug, Clone)]
+pub struct TypedRecord<T> {
+    pub created_at: u64,
+    pub value: T,
+    pub event_id: String,
+}
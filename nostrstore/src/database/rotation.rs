@@ -0,0 +1,231 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use nostr_sdk::prelude::*;
+
+use super::batch::apply_tombstones;
+use super::core::{Database, NOSTR_STORE_AGGREGATE_KIND, NOSTR_STORE_KIND};
+use super::keys::{KeyScheme, Keyring};
+use super::record::aggregate_records;
+use super::{NostrRecord, QueryOptions};
+use crate::NostrDBError;
+
+/// The progress of a rotation to a new identity, returned by `Database::rotate_to`.
+/// Keys are moved one at a time: a rotated key is only stored by the new identity, and a key
+/// that failed is still stored by the old one. Rotating to the same identity again resumes the
+/// rotation with the keys left.
+#[derive(Debug, Clone)]
+pub struct RotationReport {
+    /// The public key of the new identity.
+    pub public_key: PublicKey,
+    /// The keys moved to the new identity.
+    pub rotated: Vec<String>,
+    /// The keys that could not be moved, with the reason.
+    pub failed: HashMap<String, String>,
+}
+
+impl RotationReport {
+    /// Returns whether every key of the old identity was moved.
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+impl Database {
+    /// Moves the keys listed in the index to the identity of the given keys.
+    /// The history of each key is re-encrypted and re-tagged with the keys of the new identity,
+    /// using the same key scheme, and published as a single aggregate event whose records keep
+    /// their creation time and order. Once the key is indexed by the new identity, deletion
+    /// requests are issued for the events of the old one, including the aggregates stored under
+    /// the key in clear, and the key is removed from the old index. A bucket is rotated to the
+    /// same bucket of the new identity.
+    ///
+    /// With the derived key scheme, the storage master key is published again, encrypted to the
+    /// new identity, so the keys hashing the tags and encrypting the values stay the same.
+    ///
+    /// The old index records the progress: keys still listed in it are the ones left, and keys
+    /// already indexed by the new identity are only deleted. A rotation interrupted while copying
+    /// a key publishes its aggregate again when resumed.
+    pub async fn rotate_to(&self, new_keys: Keys) -> Result<RotationReport, NostrDBError> {
+        self.rotate_to_with_keys(new_keys, Vec::<String>::new())
+            .await
+    }
+
+    /// Moves the keys listed in the index to the identity of the given keys, like `rotate_to`,
+    /// along with the given keys, for keys missing from the index.
+    pub async fn rotate_to_with_keys<I, S>(
+        &self,
+        new_keys: Keys,
+        keys: I,
+    ) -> Result<RotationReport, NostrDBError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        // Local keys also hash the tags of the identity scheme
+        let secret_key = new_keys.secret_key().to_secret_bytes();
+        let target = self
+            .rotation_target(new_keys.into_nostr_signer(), Some(secret_key))
+            .await?;
+        self.rotate(&target, keys).await
+    }
+
+    /// Moves the keys listed in the index to the identity of the given signer, like `rotate_to`.
+    /// Only databases of the derived key scheme can be rotated to a signer holding its keys
+    /// elsewhere, such as a NIP-46 remote signer.
+    pub async fn rotate_to_signer<S>(&self, signer: S) -> Result<RotationReport, NostrDBError>
+    where
+        S: IntoNostrSigner,
+    {
+        self.rotate_to_signer_with_keys(signer, Vec::<String>::new())
+            .await
+    }
+
+    /// Moves the keys listed in the index to the identity of the given signer, like
+    /// `rotate_to_signer`, along with the given keys, for keys missing from the index.
+    pub async fn rotate_to_signer_with_keys<S, I, K>(
+        &self,
+        signer: S,
+        keys: I,
+    ) -> Result<RotationReport, NostrDBError>
+    where
+        S: IntoNostrSigner,
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        let target = self
+            .rotation_target(signer.into_nostr_signer(), None)
+            .await?;
+        self.rotate(&target, keys).await
+    }
+
+    /// Moves the keys listed in the index, and the given keys holding values, to the target.
+    async fn rotate<I, S>(&self, target: &Database, keys: I) -> Result<RotationReport, NostrDBError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let copied: BTreeSet<String> = target.list_keys().await?.into_iter().collect();
+        let indexed: BTreeSet<String> = self.list_keys().await?.into_iter().collect();
+        let candidates: BTreeSet<String> = keys.into_iter().map(Into::into).collect();
+
+        let mut report = RotationReport {
+            public_key: target.public_key,
+            rotated: Vec::new(),
+            failed: HashMap::new(),
+        };
+        for key in indexed.union(&candidates) {
+            let listed = indexed.contains(key) || copied.contains(key);
+            match self.rotate_key(target, key, copied.contains(key)).await {
+                Ok(found) if found || listed => report.rotated.push(key.clone()),
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!(key, error = %e, "Failed to rotate key.");
+                    report.failed.insert(key.clone(), e.to_string());
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns the database of the new identity, with the key scheme and bucket of this one.
+    async fn rotation_target(
+        &self,
+        signer: Arc<dyn NostrSigner>,
+        secret_key: Option<[u8; 32]>,
+    ) -> Result<Database, NostrDBError> {
+        let public_key = signer
+            .get_public_key()
+            .await
+            .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+        let root = Keyring::identity(secret_key.unwrap_or_default());
+        let mut target = self.with_identity(signer, public_key, root);
+
+        match (self.keyring.scheme(), secret_key) {
+            (KeyScheme::Identity, Some(_)) => {}
+            (KeyScheme::Identity, None) => {
+                return Err(NostrDBError::DatabaseError(
+                    "The identity key scheme requires local keys".to_string(),
+                ));
+            }
+            (KeyScheme::Derived, _) => {
+                let master = *self.keyring.root();
+                if target.adopt_storage_key(master).await? != master {
+                    return Err(NostrDBError::DatabaseError(
+                        "The new identity already has another storage key".to_string(),
+                    ));
+                }
+                target.keyring = Keyring::derived(master);
+            }
+        }
+        target.keyring = self.keyring.rebase(target.keyring.clone());
+        Ok(target)
+    }
+
+    /// Copies the history of a key to the target database, unless it was copied already,
+    /// then deletes it from this one. It returns whether the key held any record.
+    async fn rotate_key(
+        &self,
+        target: &Database,
+        key: &str,
+        copied: bool,
+    ) -> Result<bool, NostrDBError> {
+        let mut found = copied;
+        if !copied {
+            let records = self.read_history_in(key, QueryOptions::default()).await?;
+
+            // Records keep their ids, which the parents of the later records refer to
+            let mut sealed = target.read_aggregates(key, &QueryOptions::stored()).await?;
+            for record in records {
+                sealed.insert(target.seal_record(record).await?);
+            }
+            found = !sealed.is_empty();
+            if found {
+                target
+                    .publish_aggregate(key, &apply_tombstones(sealed))
+                    .await?;
+                target.index_key(key).await;
+            }
+        }
+
+        self.retire(key).await?;
+        Ok(found)
+    }
+
+    /// Issues deletion requests for the records, aggregates and chunks of a key,
+    /// and removes it from the index.
+    async fn retire(&self, key: &str) -> Result<(), NostrDBError> {
+        let filter = self
+            .get_filter(key, NOSTR_STORE_KIND)
+            .await?
+            .kind(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND));
+        // Aggregates stored under the key in clear would keep its name public
+        let filter = self.with_legacy_identifier(key, filter);
+        let events = self.fetch_events(filter, &QueryOptions::stored()).await?;
+
+        let mut ids = Vec::new();
+        for event in events.iter() {
+            ids.push(event.id);
+            let records = if event.kind == Kind::Custom(NOSTR_STORE_AGGREGATE_KIND) {
+                aggregate_records(event)?
+            } else {
+                vec![NostrRecord::from(event)]
+            };
+            ids.extend(
+                records
+                    .iter()
+                    .flat_map(|record| record.chunks.iter())
+                    .filter_map(|id| EventId::parse(id).ok()),
+            );
+        }
+
+        if !ids.is_empty() {
+            let request = EventDeletionRequest::new()
+                .ids(ids)
+                .reason("rotated to a new identity");
+            self.send_event(EventBuilder::delete(request)).await?;
+        }
+        self.unindex_key(key).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::BTreeMap;
+
+use nostr_sdk::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::acl::{ACL_KEY, AccessControl, Acl};
+use super::core::{Database, NOSTR_STORE_ENVELOPE_KIND, NOSTR_STORE_MEMBERS_KIND};
+use super::keys::Keyring;
+use super::quorum::relay_names;
+use super::{DatabaseBuilder, HistoryOrder, QueryOptions};
+use crate::NostrDBError;
+
+/// Identifier hashed into the `d` tag of the members event of a shared store.
+const MEMBERS_IDENTIFIER: &str = "nostrstore-members";
+
+/// The role of a member of a shared store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemberRole {
+    /// The member reads the values of the store. Its writes are refused.
+    Reader,
+    /// The member reads the values of the store, and its writes are read by the other members.
+    Writer,
+}
+
+/// The master keys of a shared store, sent to a member in a NIP-59 gift wrap.
+#[derive(Serialize, Deserialize)]
+struct KeyEnvelope {
+    store: String,
+    /// The hex master keys of the store, the current one last.
+    keys: Vec<String>,
+}
+
+/// A store shared by a set of members, created with `DatabaseBuilder::create_shared` and opened
+/// with `DatabaseBuilder::build_shared`.
+/// The values are encrypted with a key of the store rather than to an identity. The master keys
+/// of the store are sent by the owner to each member in a NIP-59 gift wrap, and the members and
+/// their roles are listed in an event of the owner, encrypted with the current key.
+/// Reads only accept the events of the current writers, for the keys the access control list
+/// of the store lets them write, and writes of the other keys are refused with `PermissionDenied`.
+///
+/// Removing a member generates a new master key, sent to the remaining members, so that the
+/// removed member can't read the values stored from then on. The `d` tags are still hashed with
+/// the first key of the store.
+pub struct SharedDatabase {
+    owner: PublicKey,
+    name: String,
+    /// The master keys of the store, the current one last.
+    keys: Vec<[u8; 32]>,
+    members: BTreeMap<PublicKey, MemberRole>,
+    acl: Option<Acl>,
+    database: Database,
+}
+
+impl DatabaseBuilder {
+    /// Creates a store with the given name, owned by the signer, and builds a database over it.
+    /// It returns an error if the signer already created a store with that name, or a `Timeout`
+    /// error if some read relays don't answer, since they may hold the keys of the store.
+    pub async fn create_shared<T: Into<String>>(
+        self,
+        name: T,
+    ) -> Result<SharedDatabase, NostrDBError> {
+        let database = self.build().await?;
+        SharedDatabase::create(database, name.into()).await
+    }
+
+    /// Builds a database over the store with the given name shared by the given owner.
+    /// It returns an error if the store doesn't exist or if the signer is not a member of it.
+    pub async fn build_shared<T: Into<String>>(
+        self,
+        owner: PublicKey,
+        name: T,
+    ) -> Result<SharedDatabase, NostrDBError> {
+        let database = self.build().await?;
+        let mut shared = SharedDatabase::new(database, owner, name.into());
+        shared.refresh().await?;
+        Ok(shared)
+    }
+}
+
+impl SharedDatabase {
+    fn new(database: Database, owner: PublicKey, name: String) -> Self {
+        Self {
+            owner,
+            name,
+            keys: Vec::new(),
+            members: BTreeMap::new(),
+            acl: None,
+            database,
+        }
+    }
+
+    async fn create(database: Database, name: String) -> Result<Self, NostrDBError> {
+        let owner = database.public_key;
+        let mut shared = Self::new(database, owner, name);
+
+        // Every relay must answer, so that the keys of an existing store are never replaced
+        let fetch = shared
+            .database
+            .fetch_by_relay(shared.envelopes_filter(), &QueryOptions::default())
+            .await?;
+        if !fetch.late.is_empty() {
+            return Err(NostrDBError::Timeout {
+                relays: relay_names(fetch.late.iter()),
+            });
+        }
+        let gift_wraps: Vec<Event> = fetch.answered.into_values().flatten().collect();
+        if shared.open_envelopes(gift_wraps.iter()).await?.is_some() {
+            return Err(NostrDBError::DatabaseError(format!(
+                "The shared store {} already exists",
+                shared.name
+            )));
+        }
+
+        shared.keys = vec![rand::random()];
+        shared.members.insert(owner, MemberRole::Writer);
+        shared.apply().await;
+        shared.wrap_keys(owner).await?;
+        shared.publish_members().await?;
+
+        shared.refresh().await?;
+        Ok(shared)
+    }
+
+    /// Returns the name of the store.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the public key of the owner of the store.
+    pub fn owner(&self) -> PublicKey {
+        self.owner
+    }
+
+    /// Returns the members of the store, including the owner, as of the last refresh.
+    pub fn members(&self) -> &BTreeMap<PublicKey, MemberRole> {
+        &self.members
+    }
+
+    /// Returns the access control list of the store as of the last refresh, if the owner
+    /// published one.
+    pub fn acl(&self) -> Option<&Acl> {
+        self.acl.as_ref()
+    }
+
+    /// Returns the database over the shared store, exposing its whole API.
+    pub fn database(&self) -> &Database {
+        &self.database
+    }
+
+    /// Loads the latest keys and members of the store, published by the owner.
+    /// It returns an error if the signer is no longer a member of the store.
+    pub async fn refresh(&mut self) -> Result<(), NostrDBError> {
+        self.keys = match self.fetch_keys().await? {
+            Some(keys) => keys,
+            // The owner is sent the keys of the stores it creates
+            None if self.database.public_key == self.owner => {
+                return Err(NostrDBError::DatabaseError(format!(
+                    "The shared store {} doesn't exist",
+                    self.name
+                )));
+            }
+            None => return Err(self.not_a_member()),
+        };
+        self.apply().await;
+
+        self.members = self.fetch_members().await?;
+        if !self.members.contains_key(&self.database.public_key) {
+            return Err(self.not_a_member());
+        }
+        self.apply().await;
+
+        self.acl = self.fetch_acl().await?;
+        self.apply().await;
+        Ok(())
+    }
+
+    /// Publishes the access control list of the store, replacing the previous one.
+    /// Only the owner can publish it. The previous lists stay in the history of `ACL_KEY`.
+    /// The list is encrypted like the values of the store, so every member can read it.
+    pub async fn set_acl(&mut self, acl: Acl) -> Result<(), NostrDBError> {
+        self.ensure_owner()?;
+        self.database
+            .store(ACL_KEY, &serde_json::to_string(&acl)?)
+            .await?;
+        self.acl = Some(acl);
+        self.apply().await;
+        Ok(())
+    }
+
+    /// Adds a member to the store, or changes its role. Only the owner can manage the members.
+    /// A new member is sent the master keys of the store, so it can read the whole history.
+    pub async fn add_member(
+        &mut self,
+        public_key: PublicKey,
+        role: MemberRole,
+    ) -> Result<(), NostrDBError> {
+        self.ensure_owner()?;
+        if public_key == self.owner {
+            return Err(NostrDBError::DatabaseError(
+                "The owner of a shared store can't change its role".to_string(),
+            ));
+        }
+
+        if self.members.insert(public_key, role).is_none() {
+            self.wrap_keys(public_key).await?;
+        }
+        self.publish_members().await?;
+        self.apply().await;
+        Ok(())
+    }
+
+    /// Removes a member from the store. Only the owner can manage the members.
+    /// A new master key is generated and sent to the remaining members: the values stored from
+    /// then on can't be read by the removed member, and its writes are no longer read.
+    /// The values stored before stay readable by the removed member. The `d` tags are still
+    /// hashed with the first key of the store, so it can still tell which events belong to the
+    /// keys whose names it knows.
+    pub async fn remove_member(&mut self, public_key: PublicKey) -> Result<(), NostrDBError> {
+        self.ensure_owner()?;
+        if public_key == self.owner {
+            return Err(NostrDBError::DatabaseError(
+                "The owner of a shared store can't be removed".to_string(),
+            ));
+        }
+        if self.members.remove(&public_key).is_none() {
+            return Ok(());
+        }
+
+        self.keys.push(rand::random());
+        self.apply().await;
+        for member in self.members.keys() {
+            self.wrap_keys(*member).await?;
+        }
+        self.publish_members().await
+    }
+
+    /// Scopes the database to the current keys and writers of the store.
+    async fn apply(&mut self) {
+        self.database.keyring = Keyring::shared(self.keys[0], self.keys.clone());
+        self.database.authors = self
+            .members
+            .iter()
+            .filter(|(_, role)| **role == MemberRole::Writer)
+            .map(|(member, _)| *member)
+            .collect();
+        self.database.access = Some(AccessControl {
+            owner: self.owner,
+            acl: self.acl.clone(),
+        });
+        // The index is read again with the new writers
+        self.database.key_index.lock().await.keys = None;
+    }
+
+    /// Fetches the latest master keys the owner sent to the signer.
+    async fn fetch_keys(&self) -> Result<Option<Vec<[u8; 32]>>, NostrDBError> {
+        let gift_wraps = self
+            .database
+            .fetch_events(self.envelopes_filter(), &QueryOptions::default())
+            .await?;
+        self.open_envelopes(gift_wraps.iter()).await
+    }
+
+    /// Returns the filter of the gift wraps sent to the signer.
+    fn envelopes_filter(&self) -> Filter {
+        Filter::new()
+            .kind(Kind::GiftWrap)
+            .pubkey(self.database.public_key)
+    }
+
+    /// Returns the master keys of the latest envelope of the store among the given gift wraps.
+    async fn open_envelopes(
+        &self,
+        gift_wraps: impl Iterator<Item = &Event>,
+    ) -> Result<Option<Vec<[u8; 32]>>, NostrDBError> {
+        let mut latest: Option<(Timestamp, KeyEnvelope)> = None;
+        for gift_wrap in gift_wraps {
+            // Gift wraps of other applications are skipped
+            let Ok(gift) = UnwrappedGift::from_gift_wrap(&self.database.signer, gift_wrap).await
+            else {
+                continue;
+            };
+            if gift.sender != self.owner
+                || gift.rumor.kind != Kind::Custom(NOSTR_STORE_ENVELOPE_KIND)
+            {
+                continue;
+            }
+            let Ok(envelope) = serde_json::from_str::<KeyEnvelope>(&gift.rumor.content) else {
+                continue;
+            };
+            if envelope.store != self.name {
+                continue;
+            }
+            // Envelopes list every key, so the latest one holds the most
+            let newer = latest.as_ref().is_none_or(|(created_at, latest)| {
+                (envelope.keys.len(), gift.rumor.created_at) > (latest.keys.len(), *created_at)
+            });
+            if newer {
+                latest = Some((gift.rumor.created_at, envelope));
+            }
+        }
+
+        let Some((_, envelope)) = latest else {
+            return Ok(None);
+        };
+        let keys = envelope
+            .keys
+            .iter()
+            .map(|key| {
+                hex::decode(key)
+                    .ok()
+                    .and_then(|key| <[u8; 32]>::try_from(key).ok())
+                    .ok_or_else(|| {
+                        NostrDBError::DatabaseError("Key envelope is malformed".to_string())
+                    })
+            })
+            .collect::<Result<Vec<[u8; 32]>, NostrDBError>>()?;
+        Ok((!keys.is_empty()).then_some(keys))
+    }
+
+    /// Sends the master keys of the store to the given member, in a NIP-59 gift wrap.
+    async fn wrap_keys(&self, member: PublicKey) -> Result<(), NostrDBError> {
+        let envelope = KeyEnvelope {
+            store: self.name.clone(),
+            keys: self.keys.iter().map(hex::encode).collect(),
+        };
+        let rumor = EventBuilder::new(
+            Kind::Custom(NOSTR_STORE_ENVELOPE_KIND),
+            serde_json::to_string(&envelope)?,
+        )
+        .build(self.database.public_key);
+        let gift_wrap = EventBuilder::gift_wrap(&self.database.signer, &member, rumor, [])
+            .await
+            .map_err(|e| NostrDBError::NostrError(e.to_string()))?;
+
+        self.database.publish(&gift_wrap).await?;
+        Ok(())
+    }
+
+    /// Fetches the latest access control list published by the owner, if any.
+    async fn fetch_acl(&self) -> Result<Option<Acl>, NostrDBError> {
+        let options = QueryOptions::default()
+            .with_order(HistoryOrder::NewestFirst)
+            .with_limit(1);
+        let history = self.database.read_history(ACL_KEY, options).await?;
+        match history.last() {
+            Some(record) => Ok(Some(serde_json::from_str(&record.content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fetches and decrypts the members listed by the owner.
+    /// Members that can't decrypt the list were removed from the store.
+    async fn fetch_members(&self) -> Result<BTreeMap<PublicKey, MemberRole>, NostrDBError> {
+        let filter = Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_MEMBERS_KIND))
+            .author(self.owner)
+            .identifier(self.database.tag_hash(MEMBERS_IDENTIFIER));
+        let events = self
+            .database
+            .fetch_events(filter, &QueryOptions::default())
+            .await?;
+
+        let Some(event) = events.first() else {
+            return Err(self.not_a_member());
+        };
+        let content = self
+            .database
+            .nip44_decrypt(&event.pubkey, &event.content)
+            .await
+            .map_err(|_| self.not_a_member())?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Publishes the members of the store, encrypted with the current key.
+    async fn publish_members(&self) -> Result<(), NostrDBError> {
+        let encrypted = self
+            .database
+            .nip44_encrypt(&serde_json::to_string(&self.members)?)
+            .await?;
+        let builder = EventBuilder::new(Kind::Custom(NOSTR_STORE_MEMBERS_KIND), encrypted)
+            .tag(self.database.d_tag(MEMBERS_IDENTIFIER));
+
+        self.database.send_event(builder).await?;
+        Ok(())
+    }
+
+    fn ensure_owner(&self) -> Result<(), NostrDBError> {
+        if self.database.public_key != self.owner {
+            return Err(NostrDBError::DatabaseError(
+                "Only the owner of a shared store can manage its members".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn not_a_member(&self) -> NostrDBError {
+        NostrDBError::DatabaseError(format!(
+            "{} is not a member of the shared store {}",
+            self.database.public_key, self.name
+        ))
+    }
+}
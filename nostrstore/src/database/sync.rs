@@ -0,0 +1,391 @@
+use std::collections::{HashMap, HashSet};
+
+use futures::future;
+use nostr_sdk::prelude::*;
+
+use super::QueryOptions;
+use super::changes::BacklogPages;
+use super::core::{
+    Database, NOSTR_STORE_AGGREGATE_KIND, NOSTR_STORE_BATCH_KIND, NOSTR_STORE_CHUNK_KIND,
+    NOSTR_STORE_INDEX_KIND, NOSTR_STORE_KEY_KIND, NOSTR_STORE_KIND,
+};
+use super::quorum::relay_names;
+use crate::NostrDBError;
+
+/// Number of event ids requested in each filter, so that relays don't refuse the filters.
+const IDS_PER_FILTER: usize = 500;
+
+/// How a relay was brought up to date by a repair or a sync.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelaySync {
+    /// Whether the events of the relay were listed with negentropy, rather than fetched.
+    pub negentropy: bool,
+    /// The number of events the relay was missing.
+    pub missing: usize,
+    /// The number of missing events the relay accepted.
+    pub sent: usize,
+    /// The number of missing events the relay rejected.
+    pub failed: usize,
+}
+
+/// The outcome of a repair or a sync, for each relay of the pool.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub relays: HashMap<RelayUrl, RelaySync>,
+    /// The relays whose events could not be listed.
+    pub unreachable: Vec<RelayUrl>,
+}
+
+impl SyncReport {
+    /// Returns the number of events republished, over all the relays.
+    pub fn sent(&self) -> usize {
+        self.relays.values().map(|relay| relay.sent).sum()
+    }
+}
+
+/// The ids of the events held by a relay, and the events fetched while listing them.
+struct RelayListing {
+    negentropy: bool,
+    ids: HashSet<EventId>,
+    events: Vec<Event>,
+}
+
+impl Database {
+    /// Republishes the events of the given key to the relays missing them: its records, its
+    /// aggregate, the chunks of its values and the commit markers of its batches.
+    pub async fn repair<T: Into<String>>(&self, key: T) -> Result<SyncReport, NostrDBError> {
+        let key_str = key.into();
+        let options = QueryOptions::stored();
+        let mut records = self.read_non_aggregates(&key_str, &options).await?;
+        records.append(&mut self.read_aggregates(&key_str, &options).await?);
+
+        let mut filters = vec![
+            self.get_filter(&key_str, NOSTR_STORE_KIND)
+                .await?
+                .kind(Kind::Custom(NOSTR_STORE_AGGREGATE_KIND)),
+        ];
+
+        let chunks: Vec<EventId> = records
+            .iter()
+            .flat_map(|record| record.chunks.iter())
+            .filter_map(|id| EventId::parse(id).ok())
+            .collect();
+        if !chunks.is_empty() {
+            filters.push(
+                Filter::new()
+                    .kind(Kind::Custom(NOSTR_STORE_CHUNK_KIND))
+                    .authors(self.authors.iter().copied())
+                    .ids(chunks),
+            );
+        }
+
+        let batches: HashSet<String> = records
+            .iter()
+            .filter_map(|record| record.batch.clone())
+            .collect();
+        if !batches.is_empty() {
+            filters.push(
+                Filter::new()
+                    .kind(Kind::Custom(NOSTR_STORE_BATCH_KIND))
+                    .authors(self.authors.iter().copied())
+                    .custom_tags(SingleLetterTag::lowercase(Alphabet::B), batches),
+            );
+        }
+
+        self.reconcile(filters).await
+    }
+
+    /// Republishes every nostrstore event of the store's authors to the relays missing them.
+    pub async fn sync_relays(&self) -> Result<SyncReport, NostrDBError> {
+        let filter = Filter::new().authors(self.authors.iter().copied()).kinds([
+            Kind::Custom(NOSTR_STORE_KIND),
+            Kind::Custom(NOSTR_STORE_AGGREGATE_KIND),
+            Kind::Custom(NOSTR_STORE_CHUNK_KIND),
+            Kind::Custom(NOSTR_STORE_INDEX_KIND),
+            Kind::Custom(NOSTR_STORE_BATCH_KIND),
+            Kind::Custom(NOSTR_STORE_KEY_KIND),
+        ]);
+        self.reconcile(vec![filter]).await
+    }
+
+    /// Lists the events matching the filters on each relay, and sends every relay the events it is missing.
+    /// Events removed by a deletion request are not republished; the request is, instead.
+    async fn reconcile(&self, filters: Vec<Filter>) -> Result<SyncReport, NostrDBError> {
+        let relays = self.relay_pool.relays().await;
+        if relays.is_empty() {
+            return Err(NostrDBError::NoRelaysProvided);
+        }
+
+        // The cached events are the ones held locally in a negentropy reconciliation
+        let mut known: Vec<Event> = Vec::new();
+        if let Some(cache) = &self.cache {
+            for filter in filters.iter() {
+                known.extend(cache.query(filter.clone()).await?);
+            }
+        }
+
+        let listings = future::join_all(relays.iter().map(|(url, relay)| {
+            let (filters, known) = (&filters, &known);
+            async move { (url.clone(), self.list_events(relay, filters, known).await) }
+        }))
+        .await;
+
+        let mut report = SyncReport::default();
+        let mut held: HashMap<RelayUrl, HashSet<EventId>> = HashMap::new();
+        let mut bodies: HashMap<EventId, Event> =
+            known.into_iter().map(|event| (event.id, event)).collect();
+        for (url, listing) in listings {
+            match listing {
+                Ok(listing) => {
+                    report.relays.insert(
+                        url.clone(),
+                        RelaySync {
+                            negentropy: listing.negentropy,
+                            ..RelaySync::default()
+                        },
+                    );
+                    held.insert(url, listing.ids);
+                    bodies.extend(listing.events.into_iter().map(|event| (event.id, event)));
+                }
+                Err(e) => {
+                    tracing::warn!(url = %url, error = %e, "Failed to list events.");
+                    report.unreachable.push(url);
+                }
+            }
+        }
+
+        if held.is_empty() {
+            return Err(NostrDBError::Timeout {
+                relays: relay_names(report.unreachable.iter()),
+            });
+        }
+
+        // The relays missing each event held by another relay
+        let mut plan: HashMap<EventId, Vec<RelayUrl>> = HashMap::new();
+        for id in held.values().flatten().collect::<HashSet<_>>() {
+            let lacking: Vec<RelayUrl> = held
+                .iter()
+                .filter(|(_, ids)| !ids.contains(id))
+                .map(|(url, _)| url.clone())
+                .collect();
+            if !lacking.is_empty() {
+                plan.insert(*id, lacking);
+            }
+        }
+        if plan.is_empty() {
+            return Ok(report);
+        }
+
+        let unknown: Vec<EventId> = plan
+            .keys()
+            .filter(|id| !bodies.contains_key(id))
+            .copied()
+            .collect();
+        for ids in unknown.chunks(IDS_PER_FILTER) {
+            let filter = Filter::new().ids(ids.iter().copied());
+            let fetched = self.fetch_events(filter, &QueryOptions::default()).await?;
+            bodies.extend(fetched.into_iter().map(|event| (event.id, event)));
+        }
+
+        self.plan_deletions(&mut plan, &mut bodies).await?;
+
+        // Expired events and replaced versions of an addressable event are not republished
+        let latest = latest_versions(bodies.values());
+        plan.retain(|id, _| {
+            bodies.get(id).is_some_and(|event| {
+                let replaced = event.kind.is_addressable()
+                    && latest
+                        .get(&address_of(event))
+                        .is_some_and(|latest| *latest > event.created_at);
+                !event.is_expired() && !replaced
+            })
+        });
+
+        // Relays break ties between versions created in the same second differently, so a relay
+        // holding a version as recent as the event is not behind
+        let held_latest: HashMap<&RelayUrl, HashMap<Address, Timestamp>> = held
+            .iter()
+            .map(|(url, ids)| {
+                (
+                    url,
+                    latest_versions(ids.iter().filter_map(|id| bodies.get(id))),
+                )
+            })
+            .collect();
+        for (id, urls) in plan.iter_mut() {
+            let event = &bodies[id];
+            if event.kind.is_addressable() {
+                let address = address_of(event);
+                urls.retain(|url| {
+                    held_latest.get(url).is_none_or(|latest| {
+                        latest
+                            .get(&address)
+                            .is_none_or(|latest| *latest < event.created_at)
+                    })
+                });
+            }
+        }
+        plan.retain(|_, urls| !urls.is_empty());
+
+        for (id, urls) in plan {
+            let event = &bodies[&id];
+            for url in urls.iter() {
+                if let Some(relay) = report.relays.get_mut(url) {
+                    relay.missing += 1;
+                }
+            }
+
+            match self.relay_pool.send_event_to(urls.clone(), event).await {
+                Ok(output) => {
+                    for url in output.success.iter() {
+                        if let Some(relay) = report.relays.get_mut(url) {
+                            relay.sent += 1;
+                        }
+                    }
+                    for url in output.failed.keys() {
+                        if let Some(relay) = report.relays.get_mut(url) {
+                            relay.failed += 1;
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(id = %id, error = %e, "Failed to republish event.");
+                    for url in urls.iter() {
+                        if let Some(relay) = report.relays.get_mut(url) {
+                            relay.failed += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Drops the events removed by a deletion request from the plan, and plans the requests
+    /// for the relays that are missing them.
+    async fn plan_deletions(
+        &self,
+        plan: &mut HashMap<EventId, Vec<RelayUrl>>,
+        bodies: &mut HashMap<EventId, Event>,
+    ) -> Result<(), NostrDBError> {
+        let ids: Vec<EventId> = plan.keys().copied().collect();
+        for ids in ids.chunks(IDS_PER_FILTER) {
+            let filter = Filter::new()
+                .kind(Kind::EventDeletion)
+                .authors(self.authors.iter().copied())
+                .events(ids.iter().copied());
+            let deletions = self
+                .fetch_by_relay(filter, &QueryOptions::default())
+                .await?;
+
+            let mut deleted: HashSet<EventId> = HashSet::new();
+            let mut holders: HashMap<EventId, HashSet<&RelayUrl>> = HashMap::new();
+            for (url, events) in deletions.answered.iter() {
+                for event in events.iter() {
+                    deleted.extend(event.tags.event_ids().copied());
+                    holders.entry(event.id).or_default().insert(url);
+                    bodies.entry(event.id).or_insert_with(|| event.clone());
+                }
+            }
+            plan.retain(|id, _| !deleted.contains(id));
+
+            for (id, holders) in holders {
+                let lacking: Vec<RelayUrl> = deletions
+                    .answered
+                    .keys()
+                    .filter(|url| !holders.contains(url))
+                    .cloned()
+                    .collect();
+                if !lacking.is_empty() {
+                    plan.insert(id, lacking);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the ids of the events matching the filters on the relay.
+    /// Relays supporting negentropy (NIP-77) reconcile their events with the known ones, and only
+    /// send the ids of the events that are not known; the others send the events.
+    async fn list_events(
+        &self,
+        relay: &Relay,
+        filters: &[Filter],
+        known: &[Event],
+    ) -> Result<RelayListing, NostrDBError> {
+        let opts = SyncOptions::default()
+            .initial_timeout(self.timeout)
+            .dry_run();
+        let mut listing = RelayListing {
+            negentropy: true,
+            ids: HashSet::new(),
+            events: Vec::new(),
+        };
+
+        for filter in filters {
+            if listing.negentropy {
+                let items: Vec<(EventId, Timestamp)> = known
+                    .iter()
+                    .filter(|event| filter.match_event(event))
+                    .map(|event| (event.id, event.created_at))
+                    .collect();
+                match relay
+                    .sync_with_items(filter.clone(), items.clone(), &opts)
+                    .await
+                {
+                    // The relay holds the known events it is not missing, and the ones it reports
+                    Ok(reconciliation) => {
+                        listing.ids.extend(
+                            items
+                                .into_iter()
+                                .map(|(id, _)| id)
+                                .filter(|id| !reconciliation.local.contains(id)),
+                        );
+                        listing.ids.extend(reconciliation.remote);
+                        continue;
+                    }
+                    Err(e) => {
+                        tracing::debug!(url = %relay.url(), error = %e, "Negentropy unavailable, fetching events.");
+                        listing.negentropy = false;
+                    }
+                }
+            }
+
+            // Relays send a limited number of events for each request, so they are fetched
+            // a time window at a time
+            let mut pages = BacklogPages::new(filter.clone(), Timestamp::from(0), Timestamp::now());
+            while let Some(events) = pages
+                .next_from(relay, self.timeout, self.exit_policy)
+                .await?
+            {
+                listing.ids.extend(events.iter().map(|event| event.id));
+                listing.events.extend(events);
+            }
+        }
+
+        Ok(listing)
+    }
+}
+
+/// The kind, author and identifier shared by the versions of an addressable event.
+type Address = (Kind, PublicKey, Option<String>);
+
+/// Returns the addressable event the given event is a version of.
+fn address_of(event: &Event) -> Address {
+    (
+        event.kind,
+        event.pubkey,
+        event.tags.identifier().map(str::to_string),
+    )
+}
+
+/// Returns the creation time of the latest version of each addressable event among the given events.
+fn latest_versions<'a>(events: impl Iterator<Item = &'a Event>) -> HashMap<Address, Timestamp> {
+    let mut latest: HashMap<Address, Timestamp> = HashMap::new();
+    for event in events.filter(|event| event.kind.is_addressable()) {
+        let created_at = latest.entry(address_of(event)).or_insert(event.created_at);
+        *created_at = (*created_at).max(event.created_at);
+    }
+    latest
+}
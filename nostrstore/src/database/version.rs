@@ -0,0 +1,214 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use nostr_sdk::{EventId, Tag, TagKind};
+
+use super::{Database, NostrRecord, QueryOptions};
+use crate::NostrDBError;
+
+/// Name of the tags referencing the versions a record was written on top of.
+pub(crate) const PARENT_TAG: &str = "parent";
+
+/// The version of a key, made of the ids of its head events.
+/// A key has several heads when concurrent `compare_and_swap` calls wrote on top of the same version.
+/// The version can be turned into a string token and parsed back.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Version {
+    ids: BTreeSet<String>,
+}
+
+impl Version {
+    /// Returns the version of a key that doesn't hold any value.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Returns the ids of the head events of the version.
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.ids.iter().map(String::as_str)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ids: Vec<&str> = self.ids().collect();
+        write!(f, "{}", ids.join(","))
+    }
+}
+
+impl FromStr for Version {
+    type Err = NostrDBError;
+
+    fn from_str(token: &str) -> Result<Self, Self::Err> {
+        let ids = token
+            .split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| {
+                EventId::parse(id)
+                    .map(|id| id.to_hex())
+                    .map_err(|e| NostrDBError::NostrError(e.to_string()))
+            })
+            .collect::<Result<BTreeSet<String>, NostrDBError>>()?;
+        Ok(Self { ids })
+    }
+}
+
+/// A value read along with the version it was read at.
+#[derive(Debug, Clone)]
+pub struct Versioned<T> {
+    pub value: T,
+    pub version: Version,
+}
+
+/// Returns the head records of the history.
+/// A record written without parents overwrites all the previous records,
+/// while a record written by `compare_and_swap` only supersedes its parents.
+pub(crate) fn heads(records: &BTreeSet<NostrRecord>) -> Vec<&NostrRecord> {
+    let superseded: HashSet<&str> = records
+        .iter()
+        .flat_map(|record| record.parents.iter().map(String::as_str))
+        .collect();
+
+    // Descendants of the last overwrite are kept even if they were created in the same second,
+    // and thus aren't necessarily ordered after it.
+    let latest = records.iter().rposition(|record| record.parents.is_empty());
+    let mut alive: HashSet<&str> = records
+        .iter()
+        .skip(latest.unwrap_or(0))
+        .map(|record| record.event_id.as_str())
+        .collect();
+    loop {
+        let descendants: Vec<&str> = records
+            .iter()
+            .filter(|record| !alive.contains(record.event_id.as_str()))
+            .filter(|record| {
+                record
+                    .parents
+                    .iter()
+                    .any(|parent| alive.contains(parent.as_str()))
+            })
+            .map(|record| record.event_id.as_str())
+            .collect();
+        if descendants.is_empty() {
+            break;
+        }
+        alive.extend(descendants);
+    }
+
+    records
+        .iter()
+        .filter(|record| alive.contains(record.event_id.as_str()))
+        .filter(|record| !superseded.contains(record.event_id.as_str()))
+        .collect()
+}
+
+/// Returns whether another record of the history was written on top of the same version
+/// as the given record. Records creating a key compete with the other records without parents
+/// created up to them.
+fn has_sibling(records: &BTreeSet<NostrRecord>, parents: &Version, written: &str) -> bool {
+    let Some(created_at) = records
+        .iter()
+        .find(|record| record.event_id == written)
+        .map(|record| record.created_at)
+    else {
+        return false;
+    };
+
+    records
+        .iter()
+        .filter(|record| record.event_id != written)
+        .any(|record| match parents.is_empty() {
+            true => record.parents.is_empty() && record.created_at <= created_at,
+            false => record
+                .parents
+                .iter()
+                .any(|parent| parents.ids.contains(parent)),
+        })
+}
+
+impl Database {
+    /// Reads the last value associated with the given key, along with its version.
+    /// The version can be passed to `compare_and_swap` to update the key only if it didn't change.
+    pub async fn read_versioned<T: Into<String>>(
+        &self,
+        key: T,
+    ) -> Result<Versioned<String>, NostrDBError> {
+        let history = self.read_history(key, QueryOptions::default()).await?;
+        let heads = heads(&history);
+        let last = heads
+            .last()
+            .ok_or_else(|| NostrDBError::DatabaseError("Variable not found".into()))?;
+
+        Ok(Versioned {
+            value: last.content.clone(),
+            version: Version {
+                ids: heads.iter().map(|record| record.event_id.clone()).collect(),
+            },
+        })
+    }
+
+    /// Stores a new value of the given key, only if the key is still at the expected version.
+    /// Use `Version::empty()` to create a key that must not exist yet.
+    /// The new event references the expected version as its parents, and the new version is returned.
+    ///
+    /// It returns a `Conflict` error, holding the competing values, if the key moved to another version.
+    /// A concurrent writer may also pass the check at the same time: both writes are then kept
+    /// as heads of the key, and a `Conflict` is returned so that the caller can merge them.
+    /// Writes made on top of the new version afterwards are not conflicts.
+    pub async fn compare_and_swap<T: Into<String>>(
+        &self,
+        key: T,
+        expected: &Version,
+        content: &str,
+    ) -> Result<Version, NostrDBError> {
+        let key_str = key.into();
+
+        let current = self.current_heads(&key_str).await?;
+        if current.0 != *expected {
+            return Err(NostrDBError::Conflict {
+                key: key_str,
+                version: current.0,
+                values: current.1,
+            });
+        }
+
+        let parent_tags = expected
+            .ids()
+            .map(|id| Tag::custom(TagKind::custom(PARENT_TAG), vec![id]))
+            .collect();
+        let receipt = self.store_with_tags(&key_str, content, parent_tags).await?;
+        let written = receipt.event_id.to_hex();
+
+        // Another writer may have swapped the same version concurrently
+        let history = self.read_history(&key_str, QueryOptions::default()).await?;
+        if has_sibling(&history, expected, &written) {
+            let after = self.current_heads(&key_str).await?;
+            return Err(NostrDBError::Conflict {
+                key: key_str,
+                version: after.0,
+                values: after.1,
+            });
+        }
+
+        Ok(Version {
+            ids: BTreeSet::from([written]),
+        })
+    }
+
+    /// Returns the current version of the key, along with the values of its heads.
+    async fn current_heads(&self, key: &str) -> Result<(Version, Vec<String>), NostrDBError> {
+        let history = self.read_history(key, QueryOptions::default()).await?;
+        let heads = heads(&history);
+
+        let version = Version {
+            ids: heads.iter().map(|record| record.event_id.clone()).collect(),
+        };
+        let values = heads.iter().map(|record| record.content.clone()).collect();
+        Ok((version, values))
+    }
+}
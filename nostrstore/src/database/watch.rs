@@ -0,0 +1,259 @@
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+use futures::stream::{self, BoxStream, StreamExt};
+use nostr_sdk::prelude::*;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::batch::apply_tombstones;
+use super::core::{Database, NOSTR_STORE_BATCH_KIND, NOSTR_STORE_KIND, fold_operations};
+use super::{NostrRecord, QueryOptions};
+use crate::{NostrDBError, Operation};
+
+/// Subscriptions to the relays of a pool, closed when dropped.
+/// Every event is returned once, whichever relays it was received from.
+pub(crate) struct Subscription {
+    relay_pool: RelayPool,
+    ids: Vec<SubscriptionId>,
+    notifications: broadcast::Receiver<RelayPoolNotification>,
+    seen: HashSet<EventId>,
+    /// The creation time of the last event returned.
+    cursor: Option<Timestamp>,
+}
+
+impl Subscription {
+    /// Subscribes to the events matching each filter, skipping the given events.
+    pub async fn open(
+        relay_pool: &RelayPool,
+        filters: Vec<Filter>,
+        seen: HashSet<EventId>,
+    ) -> Result<Self, NostrDBError> {
+        // Listening first, so that no event is received before the subscriptions are watched
+        let notifications = relay_pool.notifications();
+        let mut subscription = Self {
+            relay_pool: relay_pool.clone(),
+            ids: Vec::new(),
+            notifications,
+            seen,
+            cursor: None,
+        };
+
+        for filter in filters {
+            let output = relay_pool
+                .subscribe(filter, SubscribeOptions::default())
+                .await
+                .map_err(|e| NostrDBError::RelayPoolError(e.to_string()))?;
+            subscription.ids.push(output.val);
+        }
+        Ok(subscription)
+    }
+
+    /// Returns the next event received by the subscriptions, or `None` once the pool is shut down.
+    /// When notifications were dropped because the subscription fell behind, returns
+    /// `SubscriptionLagged` once, then the events received after them.
+    pub async fn next(&mut self) -> Option<Result<Event, NostrDBError>> {
+        loop {
+            match self.notifications.recv().await {
+                Ok(RelayPoolNotification::Message {
+                    message:
+                        RelayMessage::Event {
+                            subscription_id,
+                            event,
+                        },
+                    ..
+                }) if self.ids.contains(&subscription_id) => {
+                    if self.seen.insert(event.id) {
+                        self.cursor = Some(event.created_at);
+                        return Some(Ok(event.into_owned()));
+                    }
+                }
+                Ok(RelayPoolNotification::Shutdown) | Err(RecvError::Closed) => return None,
+                Ok(_) => {}
+                Err(RecvError::Lagged(skipped)) => {
+                    return Some(Err(NostrDBError::SubscriptionLagged {
+                        skipped,
+                        cursor: self.cursor,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // Out of a runtime, the subscriptions are left to be closed with the pool
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let relay_pool = self.relay_pool.clone();
+        let ids = std::mem::take(&mut self.ids);
+        runtime.spawn(async move {
+            for id in ids.iter() {
+                relay_pool.unsubscribe(id).await;
+            }
+        });
+    }
+}
+
+/// Holds back the records of write batches until the commit marker of their batch arrives.
+#[derive(Default)]
+pub(crate) struct BatchGate {
+    /// The records of each write batch whose commit marker has not arrived yet.
+    uncommitted: HashMap<String, Vec<Event>>,
+    committed: HashSet<String>,
+}
+
+impl BatchGate {
+    /// Returns the events made visible by the given event, in order: the event itself,
+    /// none while its batch is not committed, or the records of the batches committed by a marker.
+    pub fn admit(&mut self, event: Event) -> Vec<Event> {
+        if event.kind == Kind::Custom(NOSTR_STORE_BATCH_KIND) {
+            let mut released = Vec::new();
+            for batch in event.tags.iter().filter_map(|tag| {
+                (tag.single_letter_tag() == Some(SingleLetterTag::lowercase(Alphabet::B)))
+                    .then(|| tag.content())
+                    .flatten()
+            }) {
+                self.committed.insert(batch.to_string());
+                if let Some(mut records) = self.uncommitted.remove(batch) {
+                    records.sort_by_cached_key(|event| NostrRecord::from(event));
+                    released.extend(records);
+                }
+            }
+            return released;
+        }
+
+        let batch = (event.kind == Kind::Custom(NOSTR_STORE_KIND))
+            .then(|| NostrRecord::from(&event).batch)
+            .flatten();
+        match batch {
+            Some(batch) if !self.committed.contains(&batch) => {
+                self.uncommitted.entry(batch).or_default().push(event);
+                Vec::new()
+            }
+            _ => vec![event],
+        }
+    }
+}
+
+/// Follows the records of a key stored from now on.
+struct Watch {
+    subscription: Subscription,
+    gate: BatchGate,
+    ready: VecDeque<Event>,
+}
+
+impl Watch {
+    async fn open(database: &Database, key: &str) -> Result<Self, NostrDBError> {
+        let now = Timestamp::now();
+        let records = database.get_filter(key, NOSTR_STORE_KIND).await?.since(now);
+        let markers = Filter::new()
+            .kind(Kind::Custom(NOSTR_STORE_BATCH_KIND))
+            .authors(database.authors.iter().copied())
+            .since(now);
+
+        // Relays also send the events stored in the current second, before the new ones
+        let stored = database
+            .fetch_events(records.clone(), &QueryOptions::stored())
+            .await?
+            .iter()
+            .map(|event| event.id)
+            .collect();
+        let filters = vec![records, markers];
+
+        Ok(Self {
+            subscription: Subscription::open(&database.relay_pool, filters, stored).await?,
+            gate: BatchGate::default(),
+            ready: VecDeque::new(),
+        })
+    }
+
+    /// Returns the next visible record of the key, as it was stored.
+    async fn next(&mut self) -> Option<Result<(PublicKey, NostrRecord), NostrDBError>> {
+        loop {
+            let Some(event) = self.ready.pop_front() else {
+                match self.subscription.next().await? {
+                    Ok(event) => self.ready.extend(self.gate.admit(event)),
+                    Err(e) => return Some(Err(e)),
+                }
+                continue;
+            };
+
+            let record = NostrRecord::from(&event);
+            if !record.is_expired(Timestamp::now().as_u64()) {
+                return Some(Ok((event.pubkey, record)));
+            }
+        }
+    }
+}
+
+impl Database {
+    /// Watches the given key for new values.
+    /// The stream yields the records of the key stored from now on, decrypted, as the relays
+    /// send them, including the tombstones of the removals made in a write batch. The records of
+    /// a write batch are yielded once the batch is committed.
+    /// When records were lost because the stream was not polled fast enough, it yields
+    /// `SubscriptionLagged`, then goes on with the next records.
+    /// The subscription is closed when the stream is dropped.
+    pub async fn watch<T: Into<String>>(
+        &self,
+        key: T,
+    ) -> Result<BoxStream<'_, Result<NostrRecord, NostrDBError>>, NostrDBError> {
+        let watch = Watch::open(self, &key.into()).await?;
+
+        Ok(stream::unfold(watch, move |mut watch| async move {
+            let record = match watch.next().await? {
+                Ok((pubkey, record)) => self.open_record(&pubkey, record).await,
+                Err(e) => Err(e),
+            };
+            Some((record, watch))
+        })
+        .boxed())
+    }
+
+    /// Watches the event-stream of the given key, processed by the given operation.
+    /// The stream yields the value folded from the whole history of the key after each new operation,
+    /// or `SubscriptionLagged` when operations were lost, like `watch`.
+    pub async fn watch_event<O>(
+        &self,
+        key: impl Into<String>,
+    ) -> Result<BoxStream<'_, Result<O::Value, NostrDBError>>, NostrDBError>
+    where
+        O: Operation + 'static,
+        O::Value: Send,
+    {
+        let key_str = key.into();
+        // Watching first, so that no operation is stored between the read and the watch
+        let watch = Watch::open(self, &key_str).await?;
+        let records: BTreeSet<NostrRecord> =
+            self.read_history(&key_str, QueryOptions::default()).await?;
+
+        Ok(stream::unfold(
+            (watch, records),
+            move |(mut watch, mut records)| async move {
+                let (pubkey, record) = loop {
+                    let (pubkey, record) = match watch.next().await? {
+                        Ok(next) => next,
+                        Err(e) => return Some((Err(e), (watch, records))),
+                    };
+                    // Operations stored while the history was read are part of it already
+                    if !records.iter().any(|read| read.event_id == record.event_id) {
+                        break (pubkey, record);
+                    }
+                };
+                let value = match self.open_record(&pubkey, record).await {
+                    Ok(record) => {
+                        records.insert(record);
+                        records = apply_tombstones(records);
+                        let mut operations = records.clone();
+                        operations.retain(|record| !record.tombstone);
+                        fold_operations::<O>(operations)
+                    }
+                    Err(e) => Err(e),
+                };
+                Some((value, (watch, records)))
+            },
+        )
+        .boxed())
+    }
+}
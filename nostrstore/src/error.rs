@@ -1,8 +1,9 @@
-use hmac::digest::InvalidLength;
 use thiserror::Error;
 
 use nostr_sdk::prelude::*;
 
+use crate::database::{StoreReceipt, Version};
+
 /// Custom error type for the NostrDB library.
 ///
 /// This enum represents various errors that can occur within the library.
@@ -38,6 +39,61 @@ pub enum NostrDBError {
     #[error("Generate tag error: {0}")]
     GenerateTagError(String),
 
+    #[error("Codec error: {0}")]
+    CodecError(String),
+
+    // decoding error of a stored value
+    #[error("Failed to decode value of key '{key}': {reason}")]
+    DecodeError { key: String, reason: String },
+
+    #[error("Compression error: {0}")]
+    CompressionError(String),
+
+    #[error("Chunk {0} of a chunked value was not found on any relay")]
+    MissingChunk(String),
+
+    #[error("Chunked value does not match its hash {0}")]
+    ChunkHashMismatch(String),
+
+    // the key moved to another version than the one expected by a compare-and-swap
+    #[error("Conflict on key '{key}': the key is at version '{version}'")]
+    Conflict {
+        key: String,
+        version: Version,
+        values: Vec<String>,
+    },
+
+    // none of the relays answered a request within the timeout
+    #[error("Relays did not answer in time: {}", relays.join(", "))]
+    Timeout { relays: Vec<String> },
+
+    // fewer relays than the write quorum accepted an event
+    #[error(
+        "Write quorum not met for event {}: {} of {required} required relays accepted it",
+        receipt.event_id,
+        receipt.accepted.len()
+    )]
+    QuorumNotMet {
+        required: usize,
+        receipt: Box<StoreReceipt>,
+    },
+
+    // too few relays returned every event of a read
+    #[error("Relays are behind: {}", behind.join(", "))]
+    InconsistentRead { behind: Vec<String> },
+
+    // the signer may not write the key of a shared store
+    #[error("Not allowed to write key '{key}' of the shared store")]
+    PermissionDenied { key: String },
+
+    // a subscription fell behind the notifications of the relay pool, which dropped some of them;
+    // `cursor` is the creation time of the last event delivered before, to resume from
+    #[error("Subscription lagged behind: {skipped} notifications were lost")]
+    SubscriptionLagged {
+        skipped: u64,
+        cursor: Option<Timestamp>,
+    },
+
     #[error("Unknown error occurred")]
     Unknown,
 }
@@ -1,7 +1,18 @@
+pub mod codec;
+pub mod compression;
 pub mod database;
 pub mod error;
 pub mod operation;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-pub use database::{Database, DatabaseBuilder, QueryOptions};
+pub use codec::{Codec, CodecError, CodecKind};
+pub use compression::CompressionKind;
+pub use database::{
+    Acl, Bucket, Change, ChangeKind, ConsistencyReport, Database, DatabaseBuilder, HistoryOrder,
+    KeyScheme, MemberRole, PendingWrite, QueryOptions, ReadConsistency, RelaySync, RotationReport,
+    SharedDatabase, StoreReceipt, SyncReport, TypedRecord, Version, Versioned, WriteBatch,
+    WriteQuorum,
+};
 pub use error::NostrDBError;
-pub use operation::Operation;
\ No newline at end of file
+pub use operation::Operation;
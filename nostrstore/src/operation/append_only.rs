@@ -1,19 +1,19 @@
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{Serialize, de::DeserializeOwned};
 
 use super::Operation;
+use crate::codec::{CodecError, CodecKind};
 
-
-pub struct AppendOnlyEvent<T : Clone + Serialize + DeserializeOwned> {
+pub struct AppendOnlyEvent<T: Clone + Serialize + DeserializeOwned> {
     pub value: T,
 }
 
-impl <T : Clone + Serialize + DeserializeOwned> AppendOnlyEvent<T> {
+impl<T: Clone + Serialize + DeserializeOwned> AppendOnlyEvent<T> {
     pub fn new(value: T) -> Self {
         Self { value }
     }
 }
 
-impl <T : Clone + Serialize + DeserializeOwned> Operation for AppendOnlyEvent<T> {
+impl<T: Clone + Serialize + DeserializeOwned> Operation for AppendOnlyEvent<T> {
     type Value = Vec<T>;
 
     fn default() -> Self::Value {
@@ -21,7 +21,9 @@ impl <T : Clone + Serialize + DeserializeOwned> Operation for AppendOnlyEvent<T>
     }
 
     fn deserialize(value: String) -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self { value: serde_json::from_str(&value)? })
+        Ok(Self {
+            value: serde_json::from_str(&value)?,
+        })
     }
 
     fn serialize(&self) -> Result<String, Box<dyn std::error::Error>> {
@@ -32,4 +34,14 @@ impl <T : Clone + Serialize + DeserializeOwned> Operation for AppendOnlyEvent<T>
         value.push(self.value.clone());
         value
     }
-}
\ No newline at end of file
+
+    fn encode(&self, codec: CodecKind) -> Result<String, CodecError> {
+        codec.encode(&self.value)
+    }
+
+    fn decode(codec: CodecKind, payload: String) -> Result<Self, CodecError> {
+        Ok(Self {
+            value: codec.decode(&payload)?,
+        })
+    }
+}
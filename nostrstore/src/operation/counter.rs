@@ -23,12 +23,10 @@ impl Operation for CounterEvent {
     }
 
     fn serialize(&self) -> Result<String, Box<dyn std::error::Error>> {
-        Ok(
-            match self {
-                Self::Increment => "increment".to_string(),
-                Self::Decrement => "decrement".to_string(),
-            }
-        )
+        Ok(match self {
+            Self::Increment => "increment".to_string(),
+            Self::Decrement => "decrement".to_string(),
+        })
     }
 
     fn apply(&self, value: i64) -> i64 {
@@ -1,5 +1,7 @@
-pub mod counter;
 pub mod append_only;
+pub mod counter;
+
+use crate::codec::{CodecError, CodecKind};
 
 /// A trait representing an operation that can be applied to a value.
 /// This trait is used for events that can be applied to a value, such as incrementing or decrementing a counter.
@@ -10,4 +12,15 @@ pub trait Operation: Sized {
     fn deserialize(value: String) -> Result<Self, Box<dyn std::error::Error>>;
     fn serialize(&self) -> Result<String, Box<dyn std::error::Error>>;
     fn apply(&self, value: Self::Value) -> Self::Value;
+
+    /// Encodes the operation with the codec configured for its key.
+    /// Operations are stored as returned by `serialize` unless they override it.
+    fn encode(&self, _codec: CodecKind) -> Result<String, CodecError> {
+        self.serialize().map_err(|e| e.to_string().into())
+    }
+
+    /// Decodes an operation stored with the given codec.
+    fn decode(_codec: CodecKind, payload: String) -> Result<Self, CodecError> {
+        Self::deserialize(payload).map_err(|e| e.to_string().into())
+    }
 }
@@ -0,0 +1,790 @@
+//! In-process relays to test databases offline, enabled by the `testing` feature.
+//!
+//! A `MockRelay` listens on a local port and stores events in memory. Faults can be injected
+//! at any time to simulate relays losing events, answering late or rejecting requests.
+//! A `ChannelSigner` keeps the keys in a task of its own and answers signing requests over a
+//! channel, so that databases can be tested with a signer that never exposes its secret key.
+//! A `Bunker` is a NIP-46 remote signer answering through a mock relay, and a `ConnectSigner`
+//! the client reaching it, to test databases whose signer is only reached through relays.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use nostr_sdk::prelude::*;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message;
+
+use nostr_sdk::nips::nip44;
+use nostr_sdk::nips::nip46::{
+    NostrConnectMessage, NostrConnectRequest, NostrConnectResponse, NostrConnectURI, ResponseResult,
+};
+
+use crate::NostrDBError;
+use crate::database::DatabaseBuilder;
+use crate::database::cache::query_database;
+
+/// The faults injected in a mock relay. By default the relay behaves.
+#[derive(Debug, Clone, Default)]
+pub struct Faults {
+    /// Accepts the events sent by clients without storing them.
+    pub drop_events: bool,
+    /// Waits for the given time before handling each message of the clients.
+    pub delay: Option<Duration>,
+    /// Rejects the events sent by clients, as with a temporary error of the relay.
+    pub reject_writes: bool,
+    /// Accepts the deletion requests sent by clients without applying or storing them.
+    pub ignore_deletions: bool,
+}
+
+struct RelayState {
+    database: MemoryDatabase,
+    faults: RwLock<Faults>,
+    /// The events stored by the relay, sent to the matching subscriptions.
+    stored: broadcast::Sender<Event>,
+}
+
+/// A relay running in the process, storing events in memory.
+/// The relay stops and closes its connections when it is dropped.
+pub struct MockRelay {
+    url: String,
+    state: Arc<RelayState>,
+    listener: JoinHandle<()>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl MockRelay {
+    /// Starts a relay listening on a free local port.
+    pub async fn run() -> Result<Self, NostrDBError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.map_err(mock_error)?;
+        let url = format!("ws://{}", listener.local_addr().map_err(mock_error)?);
+
+        let (stored, _) = broadcast::channel(1024);
+        let state = Arc::new(RelayState {
+            database: MemoryDatabase::with_opts(MemoryDatabaseOptions {
+                events: true,
+                max_events: None,
+            }),
+            faults: RwLock::new(Faults::default()),
+            stored,
+        });
+        let (shutdown, _) = watch::channel(false);
+
+        let accept_state = state.clone();
+        let accept_shutdown = shutdown.clone();
+        let listener = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(handle_connection(
+                    stream,
+                    accept_state.clone(),
+                    accept_shutdown.subscribe(),
+                ));
+            }
+        });
+
+        Ok(Self {
+            url,
+            state,
+            listener,
+            shutdown,
+        })
+    }
+
+    /// Returns the websocket url of the relay.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Returns the faults currently injected in the relay.
+    pub fn faults(&self) -> Faults {
+        self.state.faults.read().unwrap().clone()
+    }
+
+    /// Injects the given faults, replacing the previous ones.
+    pub fn set_faults(&self, faults: Faults) {
+        *self.state.faults.write().unwrap() = faults;
+    }
+
+    /// Returns the events stored by the relay, from the newest to the oldest.
+    pub async fn events(&self) -> Vec<Event> {
+        self.state
+            .database
+            .query(Filter::new())
+            .await
+            .map(|events| events.into_iter().collect())
+            .unwrap_or_default()
+    }
+
+    /// Stores the event directly, as if it had been synced from another relay.
+    /// Subscriptions are not notified.
+    pub async fn insert_event(&self, event: &Event) -> Result<(), NostrDBError> {
+        self.state
+            .database
+            .save_event(event)
+            .await
+            .map_err(mock_error)?;
+        Ok(())
+    }
+}
+
+impl Drop for MockRelay {
+    fn drop(&mut self) {
+        self.listener.abort();
+        let _ = self.shutdown.send(true);
+    }
+}
+
+impl DatabaseBuilder {
+    /// Creates a builder with generated keys, using a new mock relay.
+    /// The relay runs until the returned handle is dropped.
+    pub async fn for_testing() -> Result<(Self, MockRelay), NostrDBError> {
+        let relay = MockRelay::run().await?;
+        let builder = Self::new(Keys::generate()).with_relays(vec![relay.url().to_string()]);
+        Ok((builder, relay))
+    }
+}
+
+type Reply<T> = oneshot::Sender<Result<T, SignerError>>;
+
+/// A request sent to a channel signer.
+enum SignerRequest {
+    Sign(UnsignedEvent, Reply<Event>),
+    Nip04Encrypt(PublicKey, String, Reply<String>),
+    Nip04Decrypt(PublicKey, String, Reply<String>),
+    Nip44Encrypt(PublicKey, String, Reply<String>),
+    Nip44Decrypt(PublicKey, String, Reply<String>),
+}
+
+/// A signer answering over a channel, to test databases whose keys are held elsewhere.
+/// The keys are held by a task of their own, which answers the requests of the signer
+/// one at a time, so that the database only ever sees signed events and plaintexts.
+/// The requests don't go through relays; `ConnectSigner` does, with NIP-46.
+#[derive(Debug, Clone)]
+pub struct ChannelSigner {
+    public_key: PublicKey,
+    requests: mpsc::UnboundedSender<SignerRequest>,
+    served: Arc<AtomicUsize>,
+}
+
+impl ChannelSigner {
+    /// Starts a signer holding the given keys.
+    pub fn new(keys: Keys) -> Self {
+        Self::with_latency(keys, Duration::ZERO)
+    }
+
+    /// Starts a signer holding the given keys, answering each request after the given time,
+    /// like a signer reached through relays.
+    pub fn with_latency(keys: Keys, latency: Duration) -> Self {
+        let public_key = keys.public_key;
+        let served = Arc::new(AtomicUsize::new(0));
+        let (requests, mut rx) = mpsc::unbounded_channel::<SignerRequest>();
+
+        let counter = served.clone();
+        tokio::spawn(async move {
+            while let Some(request) = rx.recv().await {
+                if !latency.is_zero() {
+                    tokio::time::sleep(latency).await;
+                }
+                counter.fetch_add(1, Ordering::Relaxed);
+                match request {
+                    SignerRequest::Sign(unsigned, reply) => {
+                        let _ = reply.send(keys.sign_event(unsigned).await);
+                    }
+                    SignerRequest::Nip04Encrypt(public_key, content, reply) => {
+                        let _ = reply.send(keys.nip04_encrypt(&public_key, &content).await);
+                    }
+                    SignerRequest::Nip04Decrypt(public_key, content, reply) => {
+                        let _ = reply.send(keys.nip04_decrypt(&public_key, &content).await);
+                    }
+                    SignerRequest::Nip44Encrypt(public_key, content, reply) => {
+                        let _ = reply.send(keys.nip44_encrypt(&public_key, &content).await);
+                    }
+                    SignerRequest::Nip44Decrypt(public_key, content, reply) => {
+                        let _ = reply.send(keys.nip44_decrypt(&public_key, &content).await);
+                    }
+                }
+            }
+        });
+
+        Self {
+            public_key,
+            requests,
+            served,
+        }
+    }
+
+    /// Returns the number of requests the signer answered.
+    pub fn requests(&self) -> usize {
+        self.served.load(Ordering::Relaxed)
+    }
+
+    /// Sends a request to the signer and waits for its answer.
+    async fn request<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> SignerRequest,
+    ) -> Result<T, SignerError> {
+        let (reply, answer) = oneshot::channel();
+        self.requests
+            .send(request(reply))
+            .map_err(|_| SignerError::from("Signer stopped"))?;
+        answer
+            .await
+            .map_err(|_| SignerError::from("Signer stopped"))?
+    }
+}
+
+impl NostrSigner for ChannelSigner {
+    fn backend(&self) -> SignerBackend<'_> {
+        SignerBackend::Custom("channel".into())
+    }
+
+    fn get_public_key(&self) -> BoxedFuture<'_, Result<PublicKey, SignerError>> {
+        Box::pin(async move { Ok(self.public_key) })
+    }
+
+    fn sign_event(&self, unsigned: UnsignedEvent) -> BoxedFuture<'_, Result<Event, SignerError>> {
+        Box::pin(self.request(|reply| SignerRequest::Sign(unsigned, reply)))
+    }
+
+    fn nip04_encrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(
+            self.request(|reply| {
+                SignerRequest::Nip04Encrypt(*public_key, content.to_string(), reply)
+            }),
+        )
+    }
+
+    fn nip04_decrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        encrypted_content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(self.request(|reply| {
+            SignerRequest::Nip04Decrypt(*public_key, encrypted_content.to_string(), reply)
+        }))
+    }
+
+    fn nip44_encrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(
+            self.request(|reply| {
+                SignerRequest::Nip44Encrypt(*public_key, content.to_string(), reply)
+            }),
+        )
+    }
+
+    fn nip44_decrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        payload: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(
+            self.request(|reply| {
+                SignerRequest::Nip44Decrypt(*public_key, payload.to_string(), reply)
+            }),
+        )
+    }
+}
+
+/// How long a NIP-46 client waits for the answer of the remote signer to a request.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A NIP-46 remote signer answering the requests sent through a mock relay.
+/// Clients connect to it with the secret of its `bunker://` URI, and the requests of clients
+/// that didn't connect are refused. The signer stops when it is dropped.
+pub struct Bunker {
+    uri: NostrConnectURI,
+    relay_pool: RelayPool,
+    served: Arc<AtomicUsize>,
+    task: JoinHandle<()>,
+}
+
+impl Bunker {
+    /// Starts a remote signer holding the given keys, listening on the given relay.
+    pub async fn run(keys: Keys, relay: &MockRelay) -> Result<Self, NostrDBError> {
+        let relay_pool = connect_pool(relay.url()).await?;
+        let filter = Filter::new()
+            .kind(Kind::NostrConnect)
+            .pubkey(keys.public_key)
+            .since(Timestamp::now());
+        let mut notifications = relay_pool.notifications();
+        relay_pool
+            .subscribe(filter, SubscribeOptions::default())
+            .await
+            .map_err(mock_error)?;
+
+        let secret = Keys::generate().public_key.to_hex();
+        let uri = NostrConnectURI::Bunker {
+            remote_signer_public_key: keys.public_key,
+            relays: vec![RelayUrl::parse(relay.url()).map_err(mock_error)?],
+            secret: Some(secret.clone()),
+        };
+
+        let served = Arc::new(AtomicUsize::new(0));
+        let counter = served.clone();
+        let pool = relay_pool.clone();
+        let task = tokio::spawn(async move {
+            let mut clients = Vec::new();
+            loop {
+                let event = match notifications.recv().await {
+                    Ok(RelayPoolNotification::Event { event, .. }) => event,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let Some(message) = open_message(&keys, &event) else {
+                    continue;
+                };
+                let id = message.id().to_string();
+                let response = match message.to_request() {
+                    Ok(NostrConnectRequest::Connect { secret: given, .. }) => {
+                        if given.as_deref() == Some(secret.as_str()) {
+                            clients.push(event.pubkey);
+                            NostrConnectResponse::with_result(ResponseResult::Ack)
+                        } else {
+                            NostrConnectResponse::with_error("invalid secret")
+                        }
+                    }
+                    Ok(_) if !clients.contains(&event.pubkey) => {
+                        NostrConnectResponse::with_error("not connected")
+                    }
+                    Ok(request) => {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        match answer(&keys, request).await {
+                            Ok(result) => NostrConnectResponse::with_result(result),
+                            Err(e) => NostrConnectResponse::with_error(e.to_string()),
+                        }
+                    }
+                    Err(e) => NostrConnectResponse::with_error(e.to_string()),
+                };
+
+                let reply = NostrConnectMessage::response(id, response);
+                if let Ok(reply) = EventBuilder::nostr_connect(&keys, event.pubkey, reply)
+                    .and_then(|builder| builder.sign_with_keys(&keys))
+                {
+                    let _ = pool.send_event(&reply).await;
+                }
+            }
+        });
+
+        Ok(Self {
+            uri,
+            relay_pool,
+            served,
+            task,
+        })
+    }
+
+    /// Returns the `bunker://` URI clients connect with.
+    pub fn uri(&self) -> &NostrConnectURI {
+        &self.uri
+    }
+
+    /// Returns the number of requests the signer answered, besides the connections.
+    pub fn requests(&self) -> usize {
+        self.served.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Bunker {
+    fn drop(&mut self) {
+        self.task.abort();
+        let relay_pool = self.relay_pool.clone();
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn(async move { relay_pool.shutdown().await });
+        }
+    }
+}
+
+/// Answers a request of a connected NIP-46 client with the given keys.
+async fn answer(keys: &Keys, request: NostrConnectRequest) -> Result<ResponseResult, SignerError> {
+    Ok(match request {
+        NostrConnectRequest::Connect { .. } => ResponseResult::Ack,
+        NostrConnectRequest::GetPublicKey => ResponseResult::GetPublicKey(keys.public_key),
+        NostrConnectRequest::SignEvent(unsigned) => {
+            ResponseResult::SignEvent(Box::new(keys.sign_event(unsigned).await?))
+        }
+        NostrConnectRequest::GetRelays => ResponseResult::GetRelays(HashMap::new()),
+        NostrConnectRequest::Nip04Encrypt { public_key, text } => ResponseResult::Nip04Encrypt {
+            ciphertext: keys.nip04_encrypt(&public_key, &text).await?,
+        },
+        NostrConnectRequest::Nip04Decrypt {
+            public_key,
+            ciphertext,
+        } => ResponseResult::Nip04Decrypt {
+            plaintext: keys.nip04_decrypt(&public_key, &ciphertext).await?,
+        },
+        NostrConnectRequest::Nip44Encrypt { public_key, text } => ResponseResult::Nip44Encrypt {
+            ciphertext: keys.nip44_encrypt(&public_key, &text).await?,
+        },
+        NostrConnectRequest::Nip44Decrypt {
+            public_key,
+            ciphertext,
+        } => ResponseResult::Nip44Decrypt {
+            plaintext: keys.nip44_decrypt(&public_key, &ciphertext).await?,
+        },
+        NostrConnectRequest::Ping => ResponseResult::Pong,
+    })
+}
+
+/// A NIP-46 client, signing with a remote signer reached through relays, such as a `Bunker`.
+/// Every request is sent as an encrypted event and answered the same way, so that the
+/// database is tested with the latency and the failures of a remote signer.
+#[derive(Debug, Clone)]
+pub struct ConnectSigner {
+    /// The keys of the client, only used to talk to the remote signer.
+    app_keys: Keys,
+    remote_signer: PublicKey,
+    public_key: PublicKey,
+    relay_pool: RelayPool,
+}
+
+impl ConnectSigner {
+    /// Connects to the remote signer of the given `bunker://` URI.
+    pub async fn connect(uri: &NostrConnectURI) -> Result<Self, NostrDBError> {
+        let (Some(remote_signer), Some(relay)) =
+            (uri.remote_signer_public_key(), uri.relays().first())
+        else {
+            return Err(mock_error("Only bunker URIs with a relay are supported"));
+        };
+        let app_keys = Keys::generate();
+        let relay_pool = connect_pool(relay.as_str()).await?;
+        let filter = Filter::new()
+            .kind(Kind::NostrConnect)
+            .author(*remote_signer)
+            .pubkey(app_keys.public_key)
+            .since(Timestamp::now());
+        relay_pool
+            .subscribe(filter, SubscribeOptions::default())
+            .await
+            .map_err(mock_error)?;
+
+        let mut signer = Self {
+            app_keys,
+            remote_signer: *remote_signer,
+            public_key: *remote_signer,
+            relay_pool,
+        };
+        signer
+            .request(NostrConnectRequest::Connect {
+                public_key: *remote_signer,
+                secret: uri.secret().map(str::to_string),
+            })
+            .await
+            .map_err(mock_error)?;
+        signer.public_key = match signer
+            .request(NostrConnectRequest::GetPublicKey)
+            .await
+            .map_err(mock_error)?
+        {
+            ResponseResult::GetPublicKey(public_key) => public_key,
+            _ => return Err(mock_error("Unexpected answer of the remote signer")),
+        };
+        Ok(signer)
+    }
+
+    /// Sends a request to the remote signer and waits for its answer.
+    async fn request(&self, request: NostrConnectRequest) -> Result<ResponseResult, SignerError> {
+        let method = request.method();
+        let message = NostrConnectMessage::request(&request);
+        let id = message.id().to_string();
+        let event = EventBuilder::nostr_connect(&self.app_keys, self.remote_signer, message)
+            .and_then(|builder| builder.sign_with_keys(&self.app_keys))
+            .map_err(SignerError::backend)?;
+
+        // Answers are only received once listened to
+        let mut notifications = self.relay_pool.notifications();
+        self.relay_pool
+            .send_event(&event)
+            .await
+            .map_err(SignerError::backend)?;
+
+        let answer = async {
+            loop {
+                let event = match notifications.recv().await {
+                    Ok(RelayPoolNotification::Event { event, .. }) => event,
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(SignerError::from("Remote signer unreachable"));
+                    }
+                };
+                let Some(message) = open_message(&self.app_keys, &event) else {
+                    continue;
+                };
+                if event.pubkey != self.remote_signer || message.id() != id {
+                    continue;
+                }
+                let response = message.to_response(method).map_err(SignerError::backend)?;
+                if let Some(error) = response.error {
+                    return Err(SignerError::from(error));
+                }
+                return response
+                    .result
+                    .ok_or_else(|| SignerError::from("Empty answer of the remote signer"));
+            }
+        };
+        tokio::time::timeout(CONNECT_TIMEOUT, answer)
+            .await
+            .map_err(|_| SignerError::from("Remote signer timed out"))?
+    }
+}
+
+impl NostrSigner for ConnectSigner {
+    fn backend(&self) -> SignerBackend<'_> {
+        SignerBackend::NostrConnect
+    }
+
+    fn get_public_key(&self) -> BoxedFuture<'_, Result<PublicKey, SignerError>> {
+        Box::pin(async move { Ok(self.public_key) })
+    }
+
+    fn sign_event(&self, unsigned: UnsignedEvent) -> BoxedFuture<'_, Result<Event, SignerError>> {
+        Box::pin(async move {
+            match self
+                .request(NostrConnectRequest::SignEvent(unsigned))
+                .await?
+            {
+                ResponseResult::SignEvent(event) => Ok(*event),
+                _ => Err(SignerError::from("Unexpected answer of the remote signer")),
+            }
+        })
+    }
+
+    fn nip04_encrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(async move {
+            let request = NostrConnectRequest::Nip04Encrypt {
+                public_key: *public_key,
+                text: content.to_string(),
+            };
+            match self.request(request).await? {
+                ResponseResult::Nip04Encrypt { ciphertext } => Ok(ciphertext),
+                _ => Err(SignerError::from("Unexpected answer of the remote signer")),
+            }
+        })
+    }
+
+    fn nip04_decrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        encrypted_content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(async move {
+            let request = NostrConnectRequest::Nip04Decrypt {
+                public_key: *public_key,
+                ciphertext: encrypted_content.to_string(),
+            };
+            match self.request(request).await? {
+                ResponseResult::Nip04Decrypt { plaintext } => Ok(plaintext),
+                _ => Err(SignerError::from("Unexpected answer of the remote signer")),
+            }
+        })
+    }
+
+    fn nip44_encrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        content: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(async move {
+            let request = NostrConnectRequest::Nip44Encrypt {
+                public_key: *public_key,
+                text: content.to_string(),
+            };
+            match self.request(request).await? {
+                ResponseResult::Nip44Encrypt { ciphertext } => Ok(ciphertext),
+                _ => Err(SignerError::from("Unexpected answer of the remote signer")),
+            }
+        })
+    }
+
+    fn nip44_decrypt<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+        payload: &'a str,
+    ) -> BoxedFuture<'a, Result<String, SignerError>> {
+        Box::pin(async move {
+            let request = NostrConnectRequest::Nip44Decrypt {
+                public_key: *public_key,
+                ciphertext: payload.to_string(),
+            };
+            match self.request(request).await? {
+                ResponseResult::Nip44Decrypt { plaintext } => Ok(plaintext),
+                _ => Err(SignerError::from("Unexpected answer of the remote signer")),
+            }
+        })
+    }
+}
+
+/// Returns a pool connected to the given relay.
+async fn connect_pool(url: &str) -> Result<RelayPool, NostrDBError> {
+    let relay_pool = RelayPool::new();
+    relay_pool
+        .add_relay(url, RelayOptions::default())
+        .await
+        .map_err(mock_error)?;
+    relay_pool.connect().await;
+    relay_pool.wait_for_connection(CONNECT_TIMEOUT).await;
+    Ok(relay_pool)
+}
+
+/// Decrypts the NIP-46 message of an event sent to the given keys.
+fn open_message(keys: &Keys, event: &Event) -> Option<NostrConnectMessage> {
+    let json = nip44::decrypt(keys.secret_key(), &event.pubkey, &event.content).ok()?;
+    NostrConnectMessage::from_json(json).ok()
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    state: Arc<RelayState>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let Ok(socket) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let (mut sink, stream) = socket.split();
+
+    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(text) = out_rx.recv().await {
+            if sink.send(Message::text(text)).await.is_err() {
+                return;
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    // Sends the events stored from now on to the matching subscriptions of the connection
+    let subscriptions: Arc<RwLock<HashMap<SubscriptionId, Filter>>> = Arc::default();
+    let live_subscriptions = subscriptions.clone();
+    let live_tx = out_tx.clone();
+    let mut stored = state.stored.subscribe();
+    let live = tokio::spawn(async move {
+        while let Ok(event) = stored.recv().await {
+            let matching: Vec<SubscriptionId> = live_subscriptions
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|(_, filter)| filter.match_event(&event))
+                .map(|(id, _)| id.clone())
+                .collect();
+            for id in matching {
+                if live_tx
+                    .send(RelayMessage::event(id, event.clone()).as_json())
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut stream = stream.take_until(Box::pin(async move {
+        let _ = shutdown.wait_for(|stopped| *stopped).await;
+    }));
+    while let Some(Ok(message)) = stream.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let Ok(message) = ClientMessage::from_json(text.as_str()) else {
+            continue;
+        };
+
+        let faults = state.faults.read().unwrap().clone();
+        if let Some(delay) = faults.delay {
+            tokio::time::sleep(delay).await;
+        }
+
+        match message {
+            ClientMessage::Event(event) => {
+                let event = event.into_owned();
+                let _ = out_tx.send(save_event(&state, &faults, event).await.as_json());
+            }
+            ClientMessage::Req {
+                subscription_id,
+                filter,
+            } => {
+                let subscription_id = subscription_id.into_owned();
+                let filter = filter.into_owned();
+                if let Ok(events) = query_database(&state.database, filter.clone()).await {
+                    for event in events {
+                        let _ = out_tx
+                            .send(RelayMessage::event(subscription_id.clone(), event).as_json());
+                    }
+                }
+                let _ = out_tx.send(RelayMessage::eose(subscription_id.clone()).as_json());
+                subscriptions
+                    .write()
+                    .unwrap()
+                    .insert(subscription_id, filter);
+            }
+            ClientMessage::Close(subscription_id) => {
+                subscriptions.write().unwrap().remove(&subscription_id);
+            }
+            ClientMessage::NegOpen {
+                subscription_id, ..
+            } => {
+                let message = RelayMessage::NegErr {
+                    subscription_id,
+                    message: "blocked: negentropy is not supported".into(),
+                };
+                let _ = out_tx.send(message.as_json());
+            }
+            _ => {}
+        }
+    }
+
+    // Dropping the last sender closes the socket
+    live.abort();
+}
+
+/// Stores an event sent by a client, according to the injected faults, and returns the answer.
+async fn save_event(state: &RelayState, faults: &Faults, event: Event) -> RelayMessage<'static> {
+    if faults.reject_writes {
+        return RelayMessage::ok(event.id, false, "error: writes are rejected");
+    }
+    if faults.drop_events || (faults.ignore_deletions && event.kind == Kind::EventDeletion) {
+        return RelayMessage::ok(event.id, true, "");
+    }
+    // Ephemeral events, like NIP-46 messages, are only sent to the subscriptions
+    if event.kind.is_ephemeral() {
+        let id = event.id;
+        let _ = state.stored.send(event);
+        return RelayMessage::ok(id, true, "");
+    }
+
+    match state.database.save_event(&event).await {
+        Ok(SaveEventStatus::Success) => {
+            let id = event.id;
+            let _ = state.stored.send(event);
+            RelayMessage::ok(id, true, "")
+        }
+        // Like relays do, only events already stored are acknowledged without being saved
+        Ok(SaveEventStatus::Rejected(RejectedReason::Duplicate)) => {
+            RelayMessage::ok(event.id, true, "duplicate: already have this event")
+        }
+        Ok(SaveEventStatus::Rejected(reason)) => RelayMessage::ok(
+            event.id,
+            false,
+            format!("invalid: {}", format!("{:?}", reason).to_lowercase()),
+        ),
+        Err(e) => RelayMessage::ok(event.id, false, format!("error: {}", e)),
+    }
+}
+
+fn mock_error<E: std::fmt::Display>(e: E) -> NostrDBError {
+    NostrDBError::RelayPoolError(format!("Mock relay error: {}", e))
+}
@@ -1,7 +1,6 @@
 use nostrstore_derive::AppendOnlyStream;
 use serde::{Deserialize, Serialize};
 use tracing::info;
-use tracing_subscriber;
 
 use nostrstore::{
     DatabaseBuilder, QueryOptions,
@@ -55,6 +54,14 @@ async fn main() {
 
     info!("History of Name: {:?}", history_name);
 
+    // typed values
+    db.put("owner", &MyPerson::new("Denis".to_string(), 24))
+        .await
+        .unwrap();
+
+    let owner: MyPerson = db.get("owner").await.unwrap();
+    info!("Owner: {:?}", owner);
+
     // event stream
     db.store_event("my-counter", CounterEvent::Increment)
         .await
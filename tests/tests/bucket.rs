@@ -0,0 +1,66 @@
+use nostrstore::DatabaseBuilder;
+use nostrstore::operation::counter::CounterEvent;
+
+#[tokio::test]
+async fn buckets_are_isolated() {
+    let (builder, relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+    let (first, second) = (db.bucket("first"), db.bucket("second"));
+
+    db.store("config", "root").await.unwrap();
+    first.store("config", "first").await.unwrap();
+    second.store("config", "second").await.unwrap();
+    assert_eq!(db.read("config").await.unwrap(), "root");
+    assert_eq!(first.read("config").await.unwrap(), "first");
+    assert_eq!(second.read("config").await.unwrap(), "second");
+
+    // Each bucket lists its own keys, and event-streams are scoped the same way
+    first.store("only-first", "v").await.unwrap();
+    assert_eq!(
+        first.list_keys().await.unwrap(),
+        vec!["config", "only-first"]
+    );
+    assert_eq!(second.list_keys().await.unwrap(), vec!["config"]);
+    first
+        .store_event("counter", CounterEvent::Increment)
+        .await
+        .unwrap();
+    assert_eq!(
+        first.read_event::<CounterEvent>("counter").await.unwrap(),
+        1
+    );
+    assert_eq!(db.read_event::<CounterEvent>("counter").await.unwrap(), 0);
+
+    // The bucket names never appear in clear
+    assert!(relay.events().await.iter().all(|event| {
+        event
+            .tags
+            .identifier()
+            .is_none_or(|tag| !tag.contains("first") && !tag.contains("config"))
+    }));
+}
+
+#[tokio::test]
+async fn clearing_a_bucket_leaves_the_others() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+    let (dropped, kept) = (db.bucket("dropped"), db.bucket("kept"));
+
+    for key in ["a", "b"] {
+        dropped.store(key, "v").await.unwrap();
+        kept.store(key, "v").await.unwrap();
+    }
+    db.store("a", "root").await.unwrap();
+
+    dropped.clear().await.unwrap();
+    assert!(dropped.list_keys().await.unwrap().is_empty());
+    assert!(dropped.read("a").await.is_err());
+    assert!(dropped.read("b").await.is_err());
+    assert_eq!(kept.list_keys().await.unwrap(), vec!["a", "b"]);
+    assert_eq!(kept.read("b").await.unwrap(), "v");
+    assert_eq!(db.read("a").await.unwrap(), "root");
+
+    // A cleared bucket can be written again
+    dropped.store("a", "again").await.unwrap();
+    assert_eq!(dropped.read("a").await.unwrap(), "again");
+}
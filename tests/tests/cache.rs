@@ -0,0 +1,113 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use nostrstore::testing::MockRelay;
+use nostrstore::{Database, DatabaseBuilder};
+
+async fn open_cached(relay: &MockRelay, keys: &Keys, cache: &Arc<MemoryDatabase>) -> Database {
+    DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .with_local_cache(cache.clone())
+        .build()
+        .await
+        .unwrap()
+}
+
+async fn marks(cache: &MemoryDatabase) -> usize {
+    cache
+        .count(Filter::new().kind(Kind::Custom(39219)))
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn cache_marks_are_kept_per_key() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let cache = Arc::new(MemoryDatabase::with_opts(MemoryDatabaseOptions {
+        events: true,
+        max_events: None,
+    }));
+    let db = open_cached(&relay, &keys, &cache).await;
+
+    db.store("a", "one").await.unwrap();
+    db.store("b", "two").await.unwrap();
+    assert_eq!(db.read("a").await.unwrap(), "one");
+    assert_eq!(db.read("b").await.unwrap(), "two");
+    let count = marks(&cache).await;
+    assert!(count > 0);
+
+    // Reading again replaces the marks of the keys instead of adding new ones
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(db.read("a").await.unwrap(), "one");
+        assert_eq!(db.read("b").await.unwrap(), "two");
+    }
+    assert_eq!(marks(&cache).await, count);
+
+    // A restarted database resumes from the saved marks
+    db.store("a", "three").await.unwrap();
+    let restarted = open_cached(&relay, &keys, &cache).await;
+    assert_eq!(restarted.read("a").await.unwrap(), "three");
+    assert_eq!(marks(&cache).await, count);
+}
+
+#[tokio::test]
+async fn reads_within_the_staleness_are_served_from_the_cache() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let cache = Arc::new(MemoryDatabase::with_opts(MemoryDatabaseOptions {
+        events: true,
+        max_events: None,
+    }));
+    let cached = DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .with_local_cache(cache.clone())
+        .with_cache_staleness(Duration::from_secs(60))
+        .build()
+        .await
+        .unwrap();
+    let other = DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .build()
+        .await
+        .unwrap();
+
+    cached.store("k", "one").await.unwrap();
+    assert_eq!(cached.read("k").await.unwrap(), "one");
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    other.store("k", "two").await.unwrap();
+    assert_eq!(cached.read("k").await.unwrap(), "one");
+
+    let fresh = open_cached(&relay, &keys, &cache).await;
+    assert_eq!(fresh.read("k").await.unwrap(), "two");
+}
+
+#[tokio::test]
+async fn file_cache_is_kept_across_restarts() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let dir = std::env::temp_dir().join(format!("nostrstore-cache-{}", keys.public_key().to_hex()));
+
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .with_local_cache_path(&dir)
+        .build()
+        .await
+        .unwrap();
+    db.store("k", "v").await.unwrap();
+    assert_eq!(db.read("k").await.unwrap(), "v");
+    drop(db);
+
+    // The relay holding the events is gone, so only the cache has them
+    let empty = MockRelay::run().await.unwrap();
+    let restarted = DatabaseBuilder::new(keys)
+        .with_relays(vec![empty.url().to_string()])
+        .with_local_cache_path(&dir)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(restarted.read("k").await.unwrap(), "v");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
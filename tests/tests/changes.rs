@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use nostr_sdk::prelude::*;
+use nostrstore::testing::MockRelay;
+use nostrstore::{ChangeKind, Database, DatabaseBuilder, WriteBatch};
+
+async fn open(relay: &MockRelay, keys: &Keys) -> Database {
+    DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .build()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn changes_yield_the_backlog_then_new_writes() {
+    let relay = MockRelay::run().await.unwrap();
+    let db = open(&relay, &Keys::generate()).await;
+    db.store("a", "one").await.unwrap();
+    db.remove("a").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    db.store("b", "two").await.unwrap();
+
+    // The removed key left the index, so its name is given, and its records were deleted
+    let mut changes = db
+        .changes_with_keys(Timestamp::from(0), ["a"])
+        .await
+        .unwrap();
+    let mut backlog = Vec::new();
+    for _ in 0..2 {
+        let change = tokio::time::timeout(Duration::from_secs(5), changes.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        backlog.push((change.key.unwrap(), change.kind, change.record.content));
+    }
+    assert_eq!(
+        backlog,
+        vec![
+            ("a".to_string(), ChangeKind::Remove, String::new()),
+            ("b".to_string(), ChangeKind::Put, "two".to_string()),
+        ]
+    );
+
+    db.store("c", "three").await.unwrap();
+    let change = tokio::time::timeout(Duration::from_secs(5), changes.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(change.kind, ChangeKind::Put);
+    assert_eq!(change.record.content, "three");
+}
+
+#[tokio::test]
+async fn changes_walk_a_long_backlog_past_a_dense_second() {
+    let (written, relay) = (
+        MockRelay::run().await.unwrap(),
+        MockRelay::run().await.unwrap(),
+    );
+    let keys = Keys::generate();
+    let db = open(&written, &keys).await;
+    let batch = (0..600).fold(WriteBatch::new(), |batch, i| {
+        batch.store(format!("k{i}"), "v")
+    });
+    db.commit(batch).await.unwrap();
+
+    // The batch is moved a month back, leaving a month of backlog after its dense second
+    let shift = Duration::from_secs(30 * 24 * 60 * 60);
+    for event in written.events().await {
+        let moved = EventBuilder::new(event.kind, event.content.clone())
+            .tags(event.tags.clone())
+            .custom_created_at(event.created_at - shift)
+            .sign_with_keys(&keys)
+            .unwrap();
+        relay.insert_event(&moved).await.unwrap();
+    }
+
+    let db = open(&relay, &keys).await;
+    db.store("last", "v").await.unwrap();
+    let changes = db.changes(Timestamp::from(0)).await.unwrap();
+    let keys: Vec<Option<String>> = tokio::time::timeout(
+        Duration::from_secs(60),
+        changes
+            .take(601)
+            .map(|change| change.unwrap().key)
+            .collect(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(keys.len(), 601);
+    assert_eq!(keys[600].as_deref(), Some("last"));
+}
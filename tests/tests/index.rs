@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use nostrstore::testing::MockRelay;
+use nostrstore::{Database, DatabaseBuilder, WriteBatch};
+
+async fn open(relay: &MockRelay, keys: &Keys) -> Database {
+    DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .build()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn large_indexes_are_sharded_and_rebuilt_page_by_page() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let db = open(&relay, &keys).await;
+
+    // The names alone outgrow the NIP-44 plaintext limit, and the records a page of the relays
+    let names: Vec<String> = (0..600)
+        .map(|i| format!("{i:03}-{}", "k".repeat(250)))
+        .collect();
+    let batch = names
+        .iter()
+        .fold(WriteBatch::new(), |batch, key| batch.store(key, "v"));
+    db.commit(batch).await.unwrap();
+    assert_eq!(db.list_keys().await.unwrap(), names);
+
+    let shards = relay
+        .events()
+        .await
+        .into_iter()
+        .filter(|event| event.kind == Kind::Custom(39216))
+        .count();
+    assert_eq!(shards, 16);
+
+    assert_eq!(db.rebuild_index(&names).await.unwrap(), names);
+    let fresh = open(&relay, &keys).await;
+    assert_eq!(fresh.list_keys().await.unwrap(), names);
+}
+
+#[tokio::test]
+async fn index_failures_dont_fail_writes() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let db = open(&relay, &keys).await;
+
+    // Every shard is published, then replaced by an index that can't be read
+    db.rebuild_index(Vec::<String>::new()).await.unwrap();
+    let created_at = Timestamp::now() + Duration::from_secs(2);
+    for shard in relay.events().await {
+        let corrupt = EventBuilder::new(shard.kind, "corrupt")
+            .tags(shard.tags.clone())
+            .custom_created_at(created_at)
+            .sign_with_keys(&keys)
+            .unwrap();
+        relay.insert_event(&corrupt).await.unwrap();
+    }
+
+    db.store("k", "v").await.unwrap();
+    assert_eq!(db.read("k").await.unwrap(), "v");
+    assert!(db.list_keys().await.is_err());
+
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert_eq!(db.rebuild_index(["k"]).await.unwrap(), vec!["k"]);
+    assert_eq!(db.list_keys().await.unwrap(), vec!["k"]);
+}
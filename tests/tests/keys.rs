@@ -0,0 +1,140 @@
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use nostrstore::testing::{Bunker, ChannelSigner, ConnectSigner, Faults, MockRelay};
+use nostrstore::{DatabaseBuilder, KeyScheme, NostrDBError};
+
+#[tokio::test]
+async fn derived_keys_encrypt_without_the_signer() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let keys = Keys::generate();
+
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_key_scheme(KeyScheme::Derived)
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    db.store("k", "secret").await.unwrap();
+    db.bucket("b").store("k", "in bucket").await.unwrap();
+    assert_eq!(db.read("k").await.unwrap(), "secret");
+    assert_eq!(db.bucket("b").read("k").await.unwrap(), "in bucket");
+
+    // Values are encrypted with a subkey, not to the identity
+    let record = relay
+        .events()
+        .await
+        .into_iter()
+        .find(|event| event.kind == Kind::Custom(9215))
+        .unwrap();
+    assert!(
+        keys.nip44_decrypt(&keys.public_key, &record.content)
+            .await
+            .is_err()
+    );
+
+    // A remote signer of the same identity loads the same storage key, and is only asked once
+    let signer = ChannelSigner::new(keys.clone());
+    let remote = DatabaseBuilder::from_signer(signer.clone())
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(remote.read("k").await.unwrap(), "secret");
+    let requests = signer.requests();
+    assert_eq!(remote.bucket("b").read("k").await.unwrap(), "in bucket");
+    assert_eq!(signer.requests(), requests);
+
+    // The identity scheme requires local keys, and doesn't read the derived values
+    assert!(
+        DatabaseBuilder::from_signer(signer)
+            .with_key_scheme(KeyScheme::Identity)
+            .with_relays(urls.clone())
+            .build()
+            .await
+            .is_err()
+    );
+    let identity = DatabaseBuilder::new(keys)
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    assert!(identity.read("k").await.is_err());
+}
+
+#[tokio::test]
+async fn nostr_connect_signer_reads_and_writes() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let keys = Keys::generate();
+    let bunker = Bunker::run(keys.clone(), &relay).await.unwrap();
+
+    let signer = ConnectSigner::connect(bunker.uri()).await.unwrap();
+    let remote = DatabaseBuilder::from_signer(signer)
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    remote.store("k", "signed remotely").await.unwrap();
+    assert_eq!(remote.read("k").await.unwrap(), "signed remotely");
+    assert!(bunker.requests() > 0);
+
+    // The events are signed by the identity held by the bunker
+    let local = DatabaseBuilder::new(keys)
+        .with_key_scheme(KeyScheme::Derived)
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(local.read("k").await.unwrap(), "signed remotely");
+
+    // Clients without the secret of the bunker are refused
+    let mut uri = bunker.uri().clone();
+    if let NostrConnectURI::Bunker { secret, .. } = &mut uri {
+        *secret = Some("wrong".to_string());
+    }
+    assert!(ConnectSigner::connect(&uri).await.is_err());
+}
+
+#[tokio::test]
+async fn storage_key_is_kept_while_relays_are_late() {
+    let (first, second) = (
+        MockRelay::run().await.unwrap(),
+        MockRelay::run().await.unwrap(),
+    );
+    let urls = vec![first.url().to_string(), second.url().to_string()];
+    let keys = Keys::generate();
+
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_key_scheme(KeyScheme::Derived)
+        .with_relays(vec![first.url().to_string()])
+        .build()
+        .await
+        .unwrap();
+    db.store("k", "v").await.unwrap();
+
+    // The relay holding the key is late, so no key is generated
+    first.set_faults(Faults {
+        delay: Some(Duration::from_secs(2)),
+        ..Default::default()
+    });
+    let late = DatabaseBuilder::new(keys.clone())
+        .with_key_scheme(KeyScheme::Derived)
+        .with_relays(urls.clone())
+        .with_timeout(Duration::from_millis(500))
+        .build()
+        .await;
+    assert!(matches!(late, Err(NostrDBError::Timeout { .. })));
+    assert!(second.events().await.is_empty());
+
+    first.set_faults(Faults::default());
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let db = DatabaseBuilder::new(keys)
+        .with_key_scheme(KeyScheme::Derived)
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(db.read("k").await.unwrap(), "v");
+}
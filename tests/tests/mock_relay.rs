@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use nostrstore::testing::{Faults, MockRelay};
+use nostrstore::{DatabaseBuilder, NostrDBError};
+
+#[tokio::test]
+async fn dropped_events_are_acknowledged_but_lost() {
+    let (builder, relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+
+    relay.set_faults(Faults {
+        drop_events: true,
+        ..Default::default()
+    });
+    let receipt = db.store("k", "v").await.unwrap();
+    assert_eq!(receipt.accepted.len(), 1);
+    assert!(relay.events().await.is_empty());
+    assert!(db.read("k").await.is_err());
+}
+
+#[tokio::test]
+async fn delayed_relays_time_out() {
+    let (builder, relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder
+        .with_timeout(Duration::from_millis(300))
+        .build()
+        .await
+        .unwrap();
+    db.store("k", "v").await.unwrap();
+
+    relay.set_faults(Faults {
+        delay: Some(Duration::from_millis(600)),
+        ..Default::default()
+    });
+    assert!(matches!(
+        db.read("k").await,
+        Err(NostrDBError::Timeout { .. })
+    ));
+
+    // The relay answers the requests it was sent in order, so the late answer is awaited first
+    relay.set_faults(Faults::default());
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(db.read("k").await.unwrap(), "v");
+}
+
+#[tokio::test]
+async fn ignored_deletions_keep_the_events() {
+    let (builder, relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+    db.store("k", "v").await.unwrap();
+
+    relay.set_faults(Faults {
+        ignore_deletions: true,
+        ..Default::default()
+    });
+    db.remove("k").await.unwrap();
+    let records = relay
+        .events()
+        .await
+        .into_iter()
+        .filter(|event| event.kind == Kind::Custom(9215))
+        .count();
+    assert_eq!(records, 1);
+    assert!(
+        relay
+            .events()
+            .await
+            .iter()
+            .all(|event| event.kind != Kind::EventDeletion)
+    );
+}
+
+#[tokio::test]
+async fn rejected_events_are_reported() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let client = Client::new(keys.clone());
+    client.add_relay(relay.url()).await.unwrap();
+    client.connect().await;
+
+    let replaceable = |content: &str, created_at: u64| {
+        EventBuilder::new(Kind::Custom(30078), content)
+            .tag(Tag::identifier("k"))
+            .custom_created_at(Timestamp::from(created_at))
+            .sign_with_keys(&keys)
+            .unwrap()
+    };
+    let newer = replaceable("newer", 2_000_000_000);
+    let older = replaceable("older", 1_000_000_000);
+    let expired = EventBuilder::text_note("expired")
+        .tag(Tag::expiration(Timestamp::from(1_000_000_000)))
+        .sign_with_keys(&keys)
+        .unwrap();
+
+    assert_eq!(client.send_event(&newer).await.unwrap().success.len(), 1);
+    // Resent events are acknowledged, as relays do with duplicates
+    assert_eq!(client.send_event(&newer).await.unwrap().success.len(), 1);
+
+    // Replaced and expired events are refused with a reason
+    for event in [&older, &expired] {
+        let output = client.send_event(event).await.unwrap();
+        assert!(output.success.is_empty());
+        assert!(
+            output
+                .failed
+                .values()
+                .all(|reason| reason.starts_with("invalid:"))
+        );
+    }
+}
@@ -0,0 +1,271 @@
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use nostrstore::testing::{Faults, MockRelay};
+use nostrstore::{
+    DatabaseBuilder, NostrDBError, QueryOptions, ReadConsistency, WriteBatch, WriteQuorum,
+};
+
+async fn relays(count: usize) -> (Vec<MockRelay>, Vec<String>) {
+    let mut relays = Vec::new();
+    for _ in 0..count {
+        relays.push(MockRelay::run().await.unwrap());
+    }
+    let urls = relays.iter().map(|relay| relay.url().to_string()).collect();
+    (relays, urls)
+}
+
+fn reject_writes(relays: &[MockRelay], reject: bool) {
+    for relay in relays {
+        relay.set_faults(Faults {
+            reject_writes: reject,
+            ..Default::default()
+        });
+    }
+}
+
+#[tokio::test]
+async fn write_quorum_receipts() {
+    let (relays, urls) = relays(3).await;
+    let db = DatabaseBuilder::new(Keys::generate())
+        .with_relays(urls)
+        .with_write_quorum(WriteQuorum::Majority)
+        .build()
+        .await
+        .unwrap();
+
+    reject_writes(&relays[2..], true);
+    let receipt = db.store("q", "v").await.unwrap();
+    assert_eq!(receipt.accepted.len(), 2);
+    assert_eq!(receipt.rejected.len(), 1);
+    assert!(
+        receipt
+            .rejected
+            .contains_key(&RelayUrl::parse(relays[2].url()).unwrap())
+    );
+
+    reject_writes(&relays[1..], true);
+    match db.store("q", "w").await {
+        Err(NostrDBError::QuorumNotMet { required, receipt }) => {
+            assert_eq!(required, 2);
+            assert_eq!(receipt.accepted.len(), 1);
+        }
+        other => panic!("expected the quorum to be missed, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn late_relays_are_reported_and_timeouts_overridden() {
+    let (relays, urls) = relays(2).await;
+    let db = DatabaseBuilder::new(Keys::generate())
+        .with_relays(urls)
+        .with_timeout(Duration::from_millis(200))
+        .build()
+        .await
+        .unwrap();
+    db.store("k", "v").await.unwrap();
+
+    relays[1].set_faults(Faults {
+        delay: Some(Duration::from_millis(400)),
+        ..Default::default()
+    });
+    // One answer is enough by default
+    assert_eq!(db.read("k").await.unwrap(), "v");
+
+    let all = QueryOptions::default().with_consistency(ReadConsistency::All);
+    match db.read_history("k", all.clone()).await {
+        Err(NostrDBError::Timeout { relays: late }) => {
+            assert_eq!(late.len(), 1);
+            assert_eq!(
+                RelayUrl::parse(&late[0]).unwrap(),
+                RelayUrl::parse(relays[1].url()).unwrap()
+            );
+        }
+        other => panic!("expected a timeout, got {other:?}"),
+    }
+
+    // The timeout of a single read is raised, and it waits for the late relay
+    let history = db
+        .read_history("k", all.with_timeout(Duration::from_secs(10)))
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 1);
+}
+
+#[tokio::test]
+async fn read_consistency_finds_the_relays_behind() {
+    let (relays, urls) = relays(3).await;
+    let keys = Keys::generate();
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    db.store("k", "one").await.unwrap();
+
+    // The last relay misses a write
+    let partial = DatabaseBuilder::new(keys)
+        .with_relays(urls[..2].to_vec())
+        .build()
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    partial.store("k", "two").await.unwrap();
+
+    assert_eq!(db.read("k").await.unwrap(), "two");
+    let quorum = QueryOptions::default().with_consistency(ReadConsistency::Quorum);
+    assert_eq!(db.read_history("k", quorum).await.unwrap().len(), 2);
+    let all = QueryOptions::default().with_consistency(ReadConsistency::All);
+    match db.read_history("k", all.clone()).await {
+        Err(NostrDBError::InconsistentRead { behind }) => {
+            assert_eq!(behind.len(), 1);
+            assert_eq!(
+                RelayUrl::parse(&behind[0]).unwrap(),
+                RelayUrl::parse(relays[2].url()).unwrap()
+            );
+        }
+        other => panic!("expected an inconsistent read, got {other:?}"),
+    }
+
+    let report = db.consistency_report("k").await.unwrap();
+    assert!(!report.is_consistent());
+    assert_eq!(report.events.len(), 2);
+    assert_eq!(report.behind.len(), 1);
+    assert_eq!(
+        report.behind[&RelayUrl::parse(relays[2].url()).unwrap()].len(),
+        1
+    );
+
+    db.repair("k").await.unwrap();
+    assert!(db.consistency_report("k").await.unwrap().is_consistent());
+    assert_eq!(db.read_history("k", all).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn outbox_flushes_once_relays_accept() {
+    let (relays, urls) = relays(2).await;
+    let keys = Keys::generate();
+    let dir = std::env::temp_dir().join(format!("nostrstore-outbox-{}", keys.public_key.to_hex()));
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_relays(urls.clone())
+        .with_outbox(&dir)
+        .build()
+        .await
+        .unwrap();
+
+    reject_writes(&relays, true);
+    assert!(db.store("o", "one").await.unwrap().queued);
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(db.store("o", "two").await.unwrap().queued);
+    assert_eq!(db.read("o").await.unwrap(), "two");
+    assert_eq!(db.flush_outbox().await.unwrap(), 0);
+    let pending = db.pending_writes().await.len();
+    drop(db);
+
+    // The outbox survives restarts
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_relays(urls.clone())
+        .with_outbox(&dir)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(db.pending_writes().await.len(), pending);
+
+    reject_writes(&relays, false);
+    assert_eq!(db.flush_outbox().await.unwrap(), pending);
+    assert!(db.pending_writes().await.is_empty());
+
+    let reader = DatabaseBuilder::new(keys)
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(reader.read("o").await.unwrap(), "two");
+    assert_eq!(
+        reader
+            .read_history("o", QueryOptions::default())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn outbox_sets_aside_writes_rejected_for_good() {
+    let (relays, urls) = relays(1).await;
+    let keys = Keys::generate();
+    let dir =
+        std::env::temp_dir().join(format!("nostrstore-rejected-{}", keys.public_key.to_hex()));
+    let db = DatabaseBuilder::new(keys)
+        .with_relays(urls)
+        .with_outbox(&dir)
+        .build()
+        .await
+        .unwrap();
+
+    // The write expires while it is queued, so the relay rejects it once reachable
+    reject_writes(&relays, true);
+    assert!(
+        db.store_with_ttl("ttl", "gone", Duration::from_secs(1))
+            .await
+            .unwrap()
+            .queued
+    );
+    assert!(db.store("k", "v").await.unwrap().queued);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    reject_writes(&relays, false);
+    assert!(db.flush_outbox().await.unwrap() > 0);
+    let rejected = db.pending_writes().await;
+    assert!(!rejected.is_empty());
+    assert!(rejected.iter().all(|write| write.rejected));
+    assert_eq!(db.read("k").await.unwrap(), "v");
+
+    // Rejected writes don't hold the later ones
+    assert!(!db.store("k", "w").await.unwrap().queued);
+    assert_eq!(db.read("k").await.unwrap(), "w");
+
+    for write in rejected.iter() {
+        assert!(db.discard_pending_write(write.id()).await.unwrap());
+    }
+    assert!(db.pending_writes().await.is_empty());
+    assert!(!db.discard_pending_write(rejected[0].id()).await.unwrap());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn sync_copies_more_events_than_a_page() {
+    let (relays, urls) = relays(2).await;
+    let keys = Keys::generate();
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_relays(urls[..1].to_vec())
+        .build()
+        .await
+        .unwrap();
+    let batch = (0..600).fold(WriteBatch::new(), |batch, i| {
+        batch.store("k", &i.to_string())
+    });
+    db.commit(batch).await.unwrap();
+
+    // The relays don't support negentropy, so the events are fetched a page at a time
+    let db = DatabaseBuilder::new(keys)
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    let report = db.sync_relays().await.unwrap();
+    let held = relays[0].events().await.len();
+    assert!(held > 600);
+    assert_eq!(report.sent(), held);
+    assert_eq!(relays[1].events().await.len(), held);
+    assert!(
+        db.sync_relays()
+            .await
+            .unwrap()
+            .relays
+            .values()
+            .all(|relay| relay.missing == 0)
+    );
+}
@@ -0,0 +1,135 @@
+use nostr_sdk::prelude::*;
+use nostrstore::testing::{ChannelSigner, MockRelay};
+use nostrstore::{DatabaseBuilder, KeyScheme};
+
+/// Publishes an aggregate as stored before the `d` tags of aggregates were hashed.
+async fn publish_legacy_aggregate(relay: &MockRelay, keys: &Keys, key: &str, value: &str) {
+    let content = keys.nip44_encrypt(&keys.public_key, value).await.unwrap();
+    let records = serde_json::json!([{
+        "created_at": Timestamp::now().as_u64() - 60,
+        "content": content,
+        "event_id": EventId::all_zeros().to_hex(),
+    }]);
+    let event = EventBuilder::new(Kind::Custom(39215), records.to_string())
+        .tag(Tag::identifier(key))
+        .sign_with_keys(keys)
+        .unwrap();
+    relay.insert_event(&event).await.unwrap();
+}
+
+#[tokio::test]
+async fn legacy_aggregates_are_migrated_when_read() {
+    let relay = MockRelay::run().await.unwrap();
+    let keys = Keys::generate();
+    let db = DatabaseBuilder::new(keys.clone())
+        .with_relays(vec![relay.url().to_string()])
+        .build()
+        .await
+        .unwrap();
+    publish_legacy_aggregate(&relay, &keys, "legacy", "old").await;
+
+    assert_eq!(db.read("legacy").await.unwrap(), "old");
+    let aggregates: Vec<Event> = relay
+        .events()
+        .await
+        .into_iter()
+        .filter(|event| event.kind == Kind::Custom(39215))
+        .collect();
+    assert_eq!(aggregates.len(), 1);
+    assert_ne!(aggregates[0].tags.identifier(), Some("legacy"));
+    assert_eq!(db.read("legacy").await.unwrap(), "old");
+}
+
+#[tokio::test]
+async fn rotation_moves_indexed_and_given_keys() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let (old_keys, new_keys) = (Keys::generate(), Keys::generate());
+
+    let old = DatabaseBuilder::new(old_keys.clone())
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    old.store("indexed", "one").await.unwrap();
+    publish_legacy_aggregate(&relay, &old_keys, "legacy", "two").await;
+    assert_eq!(old.list_keys().await.unwrap(), vec!["indexed"]);
+
+    let report = old
+        .rotate_to_with_keys(new_keys.clone(), ["legacy", "missing"])
+        .await
+        .unwrap();
+    assert!(report.is_complete());
+    assert_eq!(report.rotated, vec!["indexed", "legacy"]);
+
+    let new = DatabaseBuilder::new(new_keys)
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(new.read("indexed").await.unwrap(), "one");
+    assert_eq!(new.read("legacy").await.unwrap(), "two");
+    assert_eq!(new.list_keys().await.unwrap(), vec!["indexed", "legacy"]);
+
+    // The old identity keeps no event naming the key in clear
+    assert!(
+        relay
+            .events()
+            .await
+            .iter()
+            .all(|event| event.tags.identifier() != Some("legacy"))
+    );
+    assert!(old.read("indexed").await.is_err());
+    assert!(old.list_keys().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn rotation_to_a_signer() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+
+    // The identity scheme hashes tags with the secret key, which a signer doesn't give out
+    let identity = DatabaseBuilder::new(Keys::generate())
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    identity.store("k", "v").await.unwrap();
+    assert!(
+        identity
+            .rotate_to_signer(ChannelSigner::new(Keys::generate()))
+            .await
+            .is_err()
+    );
+    assert_eq!(identity.read("k").await.unwrap(), "v");
+
+    let derived = DatabaseBuilder::new(Keys::generate())
+        .with_key_scheme(KeyScheme::Derived)
+        .with_relays(urls.clone())
+        .build()
+        .await
+        .unwrap();
+    derived.store("k", "v").await.unwrap();
+    derived.bucket("b").store("k", "in bucket").await.unwrap();
+
+    let new_keys = Keys::generate();
+    let report = derived
+        .rotate_to_signer(ChannelSigner::new(new_keys.clone()))
+        .await
+        .unwrap();
+    assert_eq!(report.rotated, vec!["k"]);
+    let report = derived
+        .bucket("b")
+        .rotate_to_signer(ChannelSigner::new(new_keys.clone()))
+        .await
+        .unwrap();
+    assert_eq!(report.rotated, vec!["k"]);
+
+    let rotated = DatabaseBuilder::from_signer(ChannelSigner::new(new_keys))
+        .with_relays(urls)
+        .build()
+        .await
+        .unwrap();
+    assert_eq!(rotated.read("k").await.unwrap(), "v");
+    assert_eq!(rotated.bucket("b").read("k").await.unwrap(), "in bucket");
+}
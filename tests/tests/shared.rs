@@ -0,0 +1,243 @@
+use std::time::Duration;
+
+use nostr_sdk::prelude::*;
+use nostrstore::testing::{ChannelSigner, MockRelay};
+use nostrstore::{Acl, DatabaseBuilder, MemberRole, NostrDBError, QueryOptions, WriteBatch};
+
+fn denied<T>(result: Result<T, NostrDBError>) -> bool {
+    matches!(result, Err(NostrDBError::PermissionDenied { .. }))
+}
+
+/// Returns the aggregate events signed by the given member, with the number of records they hold.
+async fn aggregates_of(relay: &MockRelay, member: &Keys) -> Vec<usize> {
+    relay
+        .events()
+        .await
+        .into_iter()
+        .filter(|event| event.kind == Kind::Custom(39215) && event.pubkey == member.public_key)
+        .map(|event| {
+            serde_json::from_str::<Vec<serde_json::Value>>(&event.content)
+                .unwrap()
+                .len()
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn readers_cannot_write() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let (owner, alice, bob) = (Keys::generate(), Keys::generate(), Keys::generate());
+
+    let mut shared = DatabaseBuilder::new(owner.clone())
+        .with_relays(urls.clone())
+        .create_shared("team")
+        .await
+        .unwrap();
+    shared.database().store("k", "v1").await.unwrap();
+    shared
+        .add_member(alice.public_key, MemberRole::Writer)
+        .await
+        .unwrap();
+    shared
+        .add_member(bob.public_key, MemberRole::Reader)
+        .await
+        .unwrap();
+
+    let writer = DatabaseBuilder::new(alice)
+        .with_relays(urls.clone())
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+    let reader = DatabaseBuilder::from_signer(ChannelSigner::new(bob))
+        .with_relays(urls)
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+    assert_eq!(reader.database().read("k").await.unwrap(), "v1");
+
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    writer.database().store("k", "v2").await.unwrap();
+    assert!(denied(reader.database().store("k", "bob").await));
+    assert!(denied(reader.database().remove("k").await));
+    assert!(denied(
+        reader
+            .database()
+            .commit(WriteBatch::new().store("k", "bob"))
+            .await
+    ));
+    assert_eq!(reader.database().read("k").await.unwrap(), "v2");
+}
+
+#[tokio::test]
+async fn acl_filters_writes() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let (owner, alice, bob) = (Keys::generate(), Keys::generate(), Keys::generate());
+
+    let mut shared = DatabaseBuilder::new(owner.clone())
+        .with_relays(urls.clone())
+        .create_shared("team")
+        .await
+        .unwrap();
+    shared
+        .add_member(alice.public_key, MemberRole::Writer)
+        .await
+        .unwrap();
+    shared
+        .add_member(bob.public_key, MemberRole::Writer)
+        .await
+        .unwrap();
+    let alice_db = DatabaseBuilder::new(alice.clone())
+        .with_relays(urls.clone())
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+    alice_db.database().store("cfg/x", "before").await.unwrap();
+
+    shared
+        .set_acl(Acl::new().allow(alice.public_key, "docs/"))
+        .await
+        .unwrap();
+    // Alice hasn't seen the ACL yet, so she can still write out of her prefixes
+    alice_db.database().store("cfg/y", "stale").await.unwrap();
+    alice_db
+        .database()
+        .store("docs/readme", "hi")
+        .await
+        .unwrap();
+
+    let bob_db = DatabaseBuilder::new(bob.clone())
+        .with_relays(urls)
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+    assert_eq!(bob_db.acl(), shared.acl());
+    assert_eq!(bob_db.database().read("docs/readme").await.unwrap(), "hi");
+    assert!(bob_db.database().read("cfg/x").await.is_err());
+    assert!(bob_db.database().read("cfg/y").await.is_err());
+    assert!(denied(bob_db.database().store("docs/readme", "bob").await));
+
+    // The owner can write anywhere
+    shared.database().store("cfg/x", "owner").await.unwrap();
+    assert_eq!(bob_db.database().read("cfg/x").await.unwrap(), "owner");
+}
+
+#[tokio::test]
+async fn reads_only_aggregate_own_records() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let (owner, alice, bob) = (Keys::generate(), Keys::generate(), Keys::generate());
+
+    let mut shared = DatabaseBuilder::new(owner.clone())
+        .with_relays(urls.clone())
+        .create_shared("team")
+        .await
+        .unwrap();
+    shared
+        .add_member(alice.public_key, MemberRole::Writer)
+        .await
+        .unwrap();
+    shared
+        .add_member(bob.public_key, MemberRole::Reader)
+        .await
+        .unwrap();
+    let writer = DatabaseBuilder::new(alice.clone())
+        .with_relays(urls.clone())
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+    let reader = DatabaseBuilder::new(bob.clone())
+        .with_relays(urls)
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+
+    shared.database().store("k", "owner").await.unwrap();
+    writer.database().store("k", "one").await.unwrap();
+    writer.database().store("k", "two").await.unwrap();
+
+    // A reader may not write the key, so reading never aggregates it
+    let aggregate_all = QueryOptions::new(true, 0);
+    let history = reader
+        .database()
+        .read_history("k", aggregate_all.clone())
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 3);
+    assert!(aggregates_of(&relay, &bob).await.is_empty());
+
+    // A writer only folds the records it signed into its aggregate
+    let history = writer
+        .database()
+        .read_history("k", aggregate_all)
+        .await
+        .unwrap();
+    assert_eq!(history.len(), 3);
+    assert_eq!(aggregates_of(&relay, &alice).await, vec![2]);
+    assert_eq!(
+        reader
+            .database()
+            .read_history("k", QueryOptions::default())
+            .await
+            .unwrap()
+            .len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn revoked_writers_stay_dropped_after_aggregation() {
+    let relay = MockRelay::run().await.unwrap();
+    let urls = vec![relay.url().to_string()];
+    let (owner, alice, bob) = (Keys::generate(), Keys::generate(), Keys::generate());
+
+    let mut shared = DatabaseBuilder::new(owner.clone())
+        .with_relays(urls.clone())
+        .create_shared("team")
+        .await
+        .unwrap();
+    for member in [&alice, &bob] {
+        shared
+            .add_member(member.public_key, MemberRole::Writer)
+            .await
+            .unwrap();
+    }
+    let mut alice_db = DatabaseBuilder::new(alice.clone())
+        .with_relays(urls.clone())
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+    let bob_db = DatabaseBuilder::new(bob.clone())
+        .with_relays(urls)
+        .build_shared(owner.public_key, "team")
+        .await
+        .unwrap();
+
+    bob_db.database().store("k", "bob").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    alice_db.database().store("k", "alice").await.unwrap();
+    alice_db
+        .database()
+        .read_history("k", QueryOptions::new(true, 0))
+        .await
+        .unwrap();
+
+    // Bob's record was not folded into Alice's aggregate, so revoking him drops it
+    shared
+        .set_acl(Acl::new().allow(alice.public_key, ""))
+        .await
+        .unwrap();
+    alice_db.refresh().await.unwrap();
+    let history = alice_db
+        .database()
+        .read_history("k", QueryOptions::default())
+        .await
+        .unwrap();
+    let values: Vec<&str> = history.iter().map(|r| r.content.as_str()).collect();
+    assert_eq!(values, vec!["alice"]);
+
+    // Removing a key only deletes the events of the writer, and shadows the others
+    alice_db.database().remove("k").await.unwrap();
+    assert!(shared.database().read("k").await.is_err());
+}
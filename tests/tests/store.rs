@@ -0,0 +1,242 @@
+use std::time::Duration;
+
+use futures::TryStreamExt;
+use nostr_sdk::{Kind, Timestamp};
+use nostrstore::operation::counter::CounterEvent;
+use nostrstore::{
+    CodecKind, CompressionKind, DatabaseBuilder, HistoryOrder, NostrDBError, QueryOptions, Version,
+    WriteBatch,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Person {
+    name: String,
+    age: u8,
+}
+
+/// Records are ordered by their creation second, so ordered writes wait for the next one.
+async fn next_second() {
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+}
+
+#[tokio::test]
+async fn put_get_with_each_codec() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder
+        .with_key_codec("cbor", CodecKind::Cbor)
+        .with_key_codec("msgpack", CodecKind::MessagePack)
+        .with_key_codec("bincode", CodecKind::Bincode)
+        .build()
+        .await
+        .unwrap();
+
+    let person = Person {
+        name: "Alice".into(),
+        age: 30,
+    };
+    for key in ["json", "cbor", "msgpack", "bincode"] {
+        db.put(key, &person).await.unwrap();
+        assert_eq!(db.get::<_, Person>(key).await.unwrap(), person, "{key}");
+    }
+    assert!(db.get::<_, u32>("cbor").await.is_err());
+}
+
+#[tokio::test]
+async fn chunked_values() {
+    let (builder, relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.with_chunk_size(100).build().await.unwrap();
+
+    let value: String = (0..5000)
+        .map(|i| char::from(b'a' + (i * 7919 % 26) as u8))
+        .collect();
+    db.store("big", &value).await.unwrap();
+    assert_eq!(db.read("big").await.unwrap(), value);
+    assert!(relay.events().await.len() > 50);
+}
+
+#[tokio::test]
+async fn compressed_values() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder
+        .with_compression(CompressionKind::Zstd, 10)
+        .build()
+        .await
+        .unwrap();
+
+    let value = "compressed ".repeat(100);
+    db.store("packed", &value).await.unwrap();
+    assert_eq!(db.read("packed").await.unwrap(), value);
+}
+
+#[tokio::test]
+async fn ttl_expiry() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+
+    db.store_with_ttl("session", "token", Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert_eq!(db.read("session").await.unwrap(), "token");
+    tokio::time::sleep(Duration::from_millis(3500)).await;
+    assert!(db.read("session").await.is_err());
+}
+
+#[tokio::test]
+async fn batch_with_tombstones() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+
+    db.store("x", "old").await.unwrap();
+    next_second().await;
+    let batch = WriteBatch::new()
+        .put("y", 5u32)
+        .remove("x")
+        .store("x", "new")
+        .store_event("counter", CounterEvent::Increment)
+        .store_event("counter", CounterEvent::Increment);
+    db.commit(batch).await.unwrap();
+
+    assert_eq!(db.get::<_, u32>("y").await.unwrap(), 5);
+    assert_eq!(db.read("x").await.unwrap(), "new");
+    assert_eq!(
+        db.read_history("x", QueryOptions::default())
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(db.read_event::<CounterEvent>("counter").await.unwrap(), 2);
+}
+
+#[tokio::test]
+async fn compare_and_swap_conflict() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+
+    let v1 = db
+        .compare_and_swap("k", &Version::empty(), "a")
+        .await
+        .unwrap();
+    assert_eq!(db.read_versioned("k").await.unwrap().version, v1);
+    let v2 = db.compare_and_swap("k", &v1, "b").await.unwrap();
+
+    match db.compare_and_swap("k", &v1, "c").await {
+        Err(NostrDBError::Conflict {
+            values, version, ..
+        }) => {
+            assert_eq!(values, vec!["b"]);
+            assert_eq!(version, v2);
+        }
+        other => panic!("expected a conflict, got {other:?}"),
+    }
+
+    let parsed: Version = v2.to_string().parse().unwrap();
+    db.compare_and_swap("k", &parsed, "d").await.unwrap();
+    assert_eq!(db.read("k").await.unwrap(), "d");
+}
+
+#[tokio::test]
+async fn history_in_both_orders() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+
+    db.store("k", "removed").await.unwrap();
+    db.commit(WriteBatch::new().remove("k").store("k", "a"))
+        .await
+        .unwrap();
+    // Aggregated records come before the ones still waiting to be aggregated
+    db.read_history("k", QueryOptions::new(true, 0))
+        .await
+        .unwrap();
+    next_second().await;
+    db.store("k", "b").await.unwrap();
+    next_second().await;
+    db.store("k", "c").await.unwrap();
+
+    let contents = |records: Vec<nostrstore::database::NostrRecord>| -> Vec<String> {
+        records.into_iter().map(|record| record.content).collect()
+    };
+    let oldest: Vec<_> = db
+        .history_stream("k", QueryOptions::default())
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(contents(oldest), ["a", "b", "c"]);
+    let newest: Vec<_> = db
+        .history_stream(
+            "k",
+            QueryOptions::default().with_order(HistoryOrder::NewestFirst),
+        )
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(contents(newest), ["c", "b", "a"]);
+
+    let first = db
+        .read_history("k", QueryOptions::default().with_limit(2))
+        .await
+        .unwrap();
+    assert_eq!(contents(first.into_iter().collect()), ["a", "b"]);
+    let last = db
+        .read_history(
+            "k",
+            QueryOptions::default()
+                .with_limit(1)
+                .with_order(HistoryOrder::NewestFirst),
+        )
+        .await
+        .unwrap();
+    assert_eq!(contents(last.into_iter().collect()), ["c"]);
+}
+
+#[tokio::test]
+async fn point_in_time_reads() {
+    let (builder, relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+
+    let before = Timestamp::now() - Duration::from_secs(1);
+    db.store("k", "one").await.unwrap();
+    db.store_event("counter", CounterEvent::Increment)
+        .await
+        .unwrap();
+    next_second().await;
+    let first = Timestamp::now();
+    next_second().await;
+    db.store("k", "two").await.unwrap();
+    db.store_event("counter", CounterEvent::Increment)
+        .await
+        .unwrap();
+
+    assert!(db.read_at("k", before).await.is_err());
+    assert_eq!(db.read_at("k", first).await.unwrap(), "one");
+    assert_eq!(db.read_at("k", Timestamp::now()).await.unwrap(), "two");
+    assert_eq!(
+        db.read_event_at::<CounterEvent>("counter", first)
+            .await
+            .unwrap(),
+        1
+    );
+
+    // Aggregated records are read at a point in time too
+    for key in ["k", "counter"] {
+        db.read_history(key, QueryOptions::new(true, 1))
+            .await
+            .unwrap();
+    }
+    let aggregates = relay
+        .events()
+        .await
+        .into_iter()
+        .filter(|event| event.kind == Kind::Custom(39215))
+        .count();
+    assert_eq!(aggregates, 2);
+    assert_eq!(db.read_at("k", first).await.unwrap(), "one");
+    assert_eq!(
+        db.read_event_at::<CounterEvent>("counter", first)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(db.read_event::<CounterEvent>("counter").await.unwrap(), 2);
+}
@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use nostrstore::operation::counter::CounterEvent;
+use nostrstore::{DatabaseBuilder, WriteBatch};
+
+/// Returns the next item of a watch, failing the test if it doesn't come.
+async fn next<S: Stream + Unpin>(stream: &mut S) -> S::Item {
+    tokio::time::timeout(Duration::from_secs(5), stream.next())
+        .await
+        .expect("no item was yielded in time")
+        .expect("the stream ended")
+}
+
+#[tokio::test]
+async fn watch_yields_the_new_records_of_the_key() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+    db.store("k", "before").await.unwrap();
+
+    let mut records = db.watch("k").await.unwrap();
+    db.store("other", "ignored").await.unwrap();
+    db.store("k", "one").await.unwrap();
+    assert_eq!(next(&mut records).await.unwrap().content, "one");
+
+    // The records of a batch are yielded once it is committed
+    let batch = WriteBatch::new()
+        .store("other", "ignored")
+        .store("k", "two");
+    db.commit(batch).await.unwrap();
+    assert_eq!(next(&mut records).await.unwrap().content, "two");
+}
+
+#[tokio::test]
+async fn watch_event_yields_the_refolded_value() {
+    let (builder, _relay) = DatabaseBuilder::for_testing().await.unwrap();
+    let db = builder.build().await.unwrap();
+    db.store_event("counter", CounterEvent::Increment)
+        .await
+        .unwrap();
+
+    let mut values = db.watch_event::<CounterEvent>("counter").await.unwrap();
+    db.store_event("counter", CounterEvent::Increment)
+        .await
+        .unwrap();
+    assert_eq!(next(&mut values).await.unwrap(), 2);
+    db.store_event("counter", CounterEvent::Decrement)
+        .await
+        .unwrap();
+    assert_eq!(next(&mut values).await.unwrap(), 1);
+}